@@ -0,0 +1,389 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use smoltcp::wire::IpCidr;
+
+use crate::{virtual_tcp_socket::MAX_CHUNK_SIZE, wireguard_helper::parse_key};
+
+/// Validated proxy configuration, see `config.toml` for an example.
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub private_key: [u8; 32],
+    /// at most one IPv4 and one IPv6 address
+    pub addresses: Vec<IpAddr>,
+    pub peers: Vec<PeerConfig>,
+    pub services: Vec<ServiceConfig>,
+    /// sessions without a valid handshake for that long are removed
+    pub session_timeout: Duration,
+    /// size of the buffer a datagram is received into
+    pub udp_buffer_size: usize,
+    /// handshake messages per second before cookie replies are sent
+    pub handshake_rate_limit: u64,
+    /// handshakes per second which are processed at all
+    pub handshake_budget: u32,
+    /// number of workers, each with its own udp sockets and sessions
+    pub workers: usize,
+}
+
+pub struct PeerConfig {
+    pub name: String,
+    pub public_key: [u8; 32],
+    pub preshared_key: Option<[u8; 32]>,
+    pub allowed_ips: Vec<IpCidr>,
+    pub tuning: TuningConfig,
+}
+
+/// Values which are set globally and can be overridden per peer.
+#[derive(Clone, Copy)]
+pub struct TuningConfig {
+    pub persistent_keepalive: Option<u16>,
+    /// mtu of the virtual device inside the tunnel
+    pub mtu: usize,
+    /// size of the receive and of the send buffer of every virtual tcp socket
+    pub tcp_buffer_size: usize,
+}
+
+/// Upper limit for `workers`, each of them takes a share of the session indices.
+const MAX_WORKERS: usize = 256;
+
+/// Overhead of wireguard data messages: header, counter and tag.
+const WIREGUARD_DATA_OVERHEAD: usize = 32;
+
+pub struct ServiceConfig {
+    pub ports: RangeInclusive<u16>,
+    /// the host is resolved on every connect
+    upstream_host: String,
+    /// upstream port of the first port in `ports`
+    upstream_port: u16,
+    /// only accept connections once the upstream is connected
+    pub connect_first: bool,
+}
+
+impl ServiceConfig {
+    /// Returns the `host:port` of the upstream for a port of this service,
+    /// port ranges are mapped one to one onto the upstream ports.
+    pub fn upstream_for(&self, port: u16) -> String {
+        let port = self.upstream_port + (port - self.ports.start());
+        format!("{}:{}", self.upstream_host, port)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    listen: Vec<String>,
+    private_key: Option<String>,
+    private_key_file: Option<PathBuf>,
+    address: RawAddresses,
+    session_timeout: Option<u64>,
+    udp_buffer_size: Option<usize>,
+    handshake_rate_limit: Option<u64>,
+    handshake_budget: Option<u32>,
+    workers: Option<usize>,
+    persistent_keepalive: Option<u16>,
+    mtu: Option<usize>,
+    tcp_buffer_size: Option<usize>,
+    #[serde(default)]
+    peers: Vec<RawPeer>,
+    #[serde(default)]
+    services: Vec<RawService>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPeer {
+    name: String,
+    public_key: String,
+    preshared_key: Option<String>,
+    #[serde(default)]
+    allowed_ips: Vec<String>,
+    persistent_keepalive: Option<u16>,
+    mtu: Option<usize>,
+    tcp_buffer_size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawService {
+    port: RawPort,
+    upstream: String,
+    #[serde(default)]
+    connect_first: bool,
+}
+
+/// either `"10.0.0.1"` or `["10.0.0.1", "fd00::1"]`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawAddresses {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+/// either `80` or `"8000-8100"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPort {
+    Single(u16),
+    Range(String),
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let raw: RawConfig = toml::from_str(&content)
+            .with_context(|| format!("failed to parse config file {}", path.display()))?;
+
+        // relative key files are resolved next to the config file
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+
+        raw.validate(base_dir)
+            .with_context(|| format!("invalid config file {}", path.display()))
+    }
+}
+
+impl RawConfig {
+    fn validate(self, base_dir: &Path) -> anyhow::Result<Config> {
+        if self.listen.is_empty() {
+            bail!("`listen` must contain at least one address");
+        }
+        let listen = self
+            .listen
+            .iter()
+            .enumerate()
+            .map(|(i, address)| {
+                address
+                    .parse::<SocketAddr>()
+                    .with_context(|| format!("invalid `listen[{}]` {:?}", i, address))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let private_key = match (self.private_key, self.private_key_file) {
+            (Some(key), None) => parse_key(key.trim()).context("invalid `private_key`")?,
+            (None, Some(file)) => {
+                let file = base_dir.join(file);
+                let key = fs::read_to_string(&file).with_context(|| {
+                    format!("failed to read `private_key_file` {}", file.display())
+                })?;
+                parse_key(key.trim()).context("invalid `private_key_file`")?
+            }
+            (Some(_), Some(_)) => {
+                bail!("only one of `private_key` and `private_key_file` may be set")
+            }
+            (None, None) => bail!("either `private_key` or `private_key_file` must be set"),
+        };
+
+        let addresses = match self.address {
+            RawAddresses::Single(address) => vec![address],
+            RawAddresses::Multiple(addresses) => addresses,
+        };
+        let addresses = addresses
+            .iter()
+            .enumerate()
+            .map(|(i, address)| {
+                address
+                    .parse::<IpAddr>()
+                    .with_context(|| format!("invalid `address[{}]` {:?}", i, address))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if addresses.is_empty() {
+            bail!("`address` must contain at least one address");
+        }
+        if addresses.iter().filter(|a| a.is_ipv4()).count() > 1
+            || addresses.iter().filter(|a| a.is_ipv6()).count() > 1
+        {
+            bail!("`address` may only contain one IPv4 and one IPv6 address");
+        }
+
+        // boringtun rejects the keys after 180s, give the peer some more time to
+        // handshake again before everything is thrown away
+        let session_timeout = Duration::from_secs(self.session_timeout.unwrap_or(240));
+        if session_timeout < Duration::from_secs(180) {
+            bail!("`session_timeout` must be at least 180 seconds");
+        }
+
+        let udp_buffer_size = self.udp_buffer_size.unwrap_or(4096 - 32);
+
+        let handshake_rate_limit = self.handshake_rate_limit.unwrap_or(100);
+        let handshake_budget = self.handshake_budget.unwrap_or(500);
+        if handshake_budget == 0 {
+            bail!("`handshake_budget` must be at least 1");
+        }
+
+        let workers = self.workers.unwrap_or(1);
+        if !(1..=MAX_WORKERS).contains(&workers) {
+            bail!("`workers` must be between 1 and {}", MAX_WORKERS);
+        }
+
+        // a persistent keepalive of 0 turns it off, like in wg-quick configs
+        let tuning = TuningConfig {
+            persistent_keepalive: Some(self.persistent_keepalive.unwrap_or(25)),
+            mtu: self.mtu.unwrap_or(1400),
+            tcp_buffer_size: self.tcp_buffer_size.unwrap_or(65535),
+        }
+        .validate(udp_buffer_size)?;
+
+        let mut peers: Vec<PeerConfig> = Vec::with_capacity(self.peers.len());
+        for (i, peer) in self.peers.into_iter().enumerate() {
+            peers.push(
+                peer.validate(&tuning, udp_buffer_size)
+                    .with_context(|| format!("invalid `peers[{}]`", i))?,
+            );
+        }
+        for (i, peer) in peers.iter().enumerate() {
+            if peers[..i].iter().any(|p| p.public_key == peer.public_key) {
+                bail!("`peers[{}].public_key`: duplicate peer public key", i);
+            }
+        }
+
+        let mut services: Vec<ServiceConfig> = Vec::with_capacity(self.services.len());
+        for (i, service) in self.services.into_iter().enumerate() {
+            services.push(
+                service
+                    .validate()
+                    .with_context(|| format!("invalid `services[{}]`", i))?,
+            );
+        }
+        if services.is_empty() {
+            bail!("`services` must contain at least one service");
+        }
+        for (i, service) in services.iter().enumerate() {
+            if let Some(j) = services[..i].iter().position(|s| {
+                s.ports.start() <= service.ports.end() && service.ports.start() <= s.ports.end()
+            }) {
+                bail!("`services[{}].port` overlaps with `services[{}].port`", i, j);
+            }
+        }
+
+        Ok(Config {
+            listen,
+            private_key,
+            addresses,
+            peers,
+            services,
+            session_timeout,
+            udp_buffer_size,
+            handshake_rate_limit,
+            handshake_budget,
+            workers,
+        })
+    }
+}
+
+impl TuningConfig {
+    fn validate(self, udp_buffer_size: usize) -> anyhow::Result<Self> {
+        if !(576..=65535).contains(&self.mtu) {
+            bail!("`mtu` must be between 576 and 65535");
+        }
+        if self.mtu + WIREGUARD_DATA_OVERHEAD > udp_buffer_size {
+            bail!(
+                "`mtu` {} doesn't fit into `udp_buffer_size` {}, which needs {} bytes for the wireguard header",
+                self.mtu,
+                udp_buffer_size,
+                WIREGUARD_DATA_OVERHEAD
+            );
+        }
+        if !(MAX_CHUNK_SIZE..=(1 << 30)).contains(&self.tcp_buffer_size) {
+            bail!(
+                "`tcp_buffer_size` must be between {} and 1073741824",
+                MAX_CHUNK_SIZE
+            );
+        }
+
+        Ok(TuningConfig {
+            persistent_keepalive: self.persistent_keepalive.filter(|k| *k > 0),
+            ..self
+        })
+    }
+}
+
+impl RawPeer {
+    fn validate(self, tuning: &TuningConfig, udp_buffer_size: usize) -> anyhow::Result<PeerConfig> {
+        if self.name.is_empty() {
+            bail!("`name` must not be empty");
+        }
+
+        let public_key = parse_key(self.public_key.trim()).context("invalid `public_key`")?;
+        let preshared_key = self
+            .preshared_key
+            .map(|key| parse_key(key.trim()).context("invalid `preshared_key`"))
+            .transpose()?;
+
+        let allowed_ips = self
+            .allowed_ips
+            .iter()
+            .enumerate()
+            .map(|(i, cidr)| {
+                cidr.parse::<IpCidr>()
+                    .map_err(|_| anyhow!("invalid `allowed_ips[{}]` {:?}", i, cidr))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let tuning = TuningConfig {
+            persistent_keepalive: self.persistent_keepalive.or(tuning.persistent_keepalive),
+            mtu: self.mtu.unwrap_or(tuning.mtu),
+            tcp_buffer_size: self.tcp_buffer_size.unwrap_or(tuning.tcp_buffer_size),
+        }
+        .validate(udp_buffer_size)?;
+
+        Ok(PeerConfig {
+            name: self.name,
+            public_key,
+            preshared_key,
+            allowed_ips,
+            tuning,
+        })
+    }
+}
+
+impl RawService {
+    fn validate(self) -> anyhow::Result<ServiceConfig> {
+        let ports = match &self.port {
+            RawPort::Single(port) => *port..=*port,
+            RawPort::Range(range) => {
+                let parsed = range.split_once('-').and_then(|(start, end)| {
+                    Some(start.trim().parse::<u16>().ok()?..=end.trim().parse::<u16>().ok()?)
+                });
+                match parsed {
+                    Some(ports) if !ports.is_empty() => ports,
+                    _ => bail!("invalid `port` {:?}, expected a port or a range like \"8000-8100\"", range),
+                }
+            }
+        };
+        if *ports.start() == 0 {
+            bail!("`port` must not be 0");
+        }
+
+        let Some((upstream_host, upstream_port)) = self
+            .upstream
+            .rsplit_once(':')
+            .filter(|(host, _)| !host.is_empty())
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        else {
+            bail!("invalid `upstream` {:?}, expected host:port", self.upstream);
+        };
+        if upstream_port == 0 {
+            bail!("`upstream` port must not be 0");
+        }
+        if upstream_port as u32 + (ports.end() - ports.start()) as u32 > u16::MAX as u32 {
+            bail!("`upstream` port range of `port` {:?} exceeds 65535", ports);
+        }
+
+        Ok(ServiceConfig {
+            ports,
+            upstream_host: upstream_host.to_string(),
+            upstream_port,
+            connect_first: self.connect_first,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use super::*;
+
+const PRIVATE_KEY: &str = "sNLSbiLbh1NzkGeoQmeVxy3YJHMlJ+6WdkggInPgN0k=";
+const PEER_KEY: &str = "LNaOi1HjTl9/gzt+HoiySiaboJ2nZe5/lAKvKHOlrhs=";
+const OTHER_PEER_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+/// Validates a config with the required settings, `globals` go before the
+/// tables and `tables` after them.
+fn validate(globals: &str, tables: &str) -> anyhow::Result<Config> {
+    let content = format!(
+        "listen = [\"127.0.0.1:51820\"]\nprivate_key = \"{}\"\naddress = \"10.0.0.1\"\n{}\n{}",
+        PRIVATE_KEY, globals, tables
+    );
+    let raw: RawConfig = toml::from_str(&content).expect("test config doesn't parse");
+    raw.validate(Path::new("."))
+}
+
+fn service(port: &str, upstream: &str) -> String {
+    format!("[[services]]\nport = {}\nupstream = \"{}\"\n", port, upstream)
+}
+
+fn error(result: anyhow::Result<Config>) -> String {
+    match result {
+        Ok(_) => panic!("config was accepted"),
+        Err(e) => format!("{:#}", e),
+    }
+}
+
+#[test]
+fn minimal_config() {
+    let config = validate("", &service("80", "localhost:8080")).unwrap();
+
+    assert_eq!(config.listen, ["127.0.0.1:51820".parse().unwrap()]);
+    assert_eq!(config.services[0].ports, 80..=80);
+    assert_eq!(config.services[0].upstream_for(80), "localhost:8080");
+}
+
+#[test]
+fn single_ports_and_ranges() {
+    let services = service("80", "localhost:8080") + &service("\"8000 - 8100\"", "localhost:9000");
+    let config = validate("", &services).unwrap();
+
+    assert_eq!(config.services[0].ports, 80..=80);
+    assert_eq!(config.services[1].ports, 8000..=8100);
+}
+
+#[test]
+fn invalid_port_ranges() {
+    for port in ["\"8100-8000\"", "\"8000-\"", "\"8000\"", "\"0-10\"", "0"] {
+        let result = validate("", &service(port, "localhost:8080"));
+        assert!(error(result).contains("port"), "{} was accepted", port);
+    }
+}
+
+#[test]
+fn overlapping_services() {
+    let services = service("\"8000-8100\"", "localhost:9000") + &service("8100", "localhost:8080");
+    let message = error(validate("", &services));
+    assert!(message.contains("`services[1].port` overlaps with `services[0].port`"), "{}", message);
+
+    // adjacent ranges don't overlap
+    let services = service("\"8000-8099\"", "localhost:9000") + &service("8100", "localhost:8080");
+    validate("", &services).unwrap();
+}
+
+#[test]
+fn upstream_port_range_must_fit() {
+    validate("", &service("\"8000-8100\"", "localhost:65435")).unwrap();
+
+    let message = error(validate("", &service("\"8000-8100\"", "localhost:65436")));
+    assert!(message.contains("exceeds 65535"), "{}", message);
+}
+
+#[test]
+fn invalid_upstreams() {
+    for upstream in ["localhost", ":80", "localhost:0", "localhost:65536"] {
+        let result = validate("", &service("80", upstream));
+        assert!(error(result).contains("upstream"), "{} was accepted", upstream);
+    }
+}
+
+#[test]
+fn upstream_for_maps_ranges_one_to_one() {
+    let services = service("\"8000-8100\"", "example.com:9000") + &service("443", "[::1]:8443");
+    let config = validate("", &services).unwrap();
+
+    assert_eq!(config.services[0].upstream_for(8000), "example.com:9000");
+    assert_eq!(config.services[0].upstream_for(8042), "example.com:9042");
+    assert_eq!(config.services[0].upstream_for(8100), "example.com:9100");
+    assert_eq!(config.services[1].upstream_for(443), "[::1]:8443");
+}
+
+#[test]
+fn peers_inherit_tuning() {
+    let tables = format!(
+        "[[peers]]\nname = \"a\"\npublic_key = \"{}\"\n\
+         [[peers]]\nname = \"b\"\npublic_key = \"{}\"\nmtu = 1280\npersistent_keepalive = 10\n{}",
+        PEER_KEY,
+        OTHER_PEER_KEY,
+        service("80", "localhost:8080")
+    );
+    let config = validate("mtu = 1300\ntcp_buffer_size = 8192\npersistent_keepalive = 0", &tables).unwrap();
+
+    let tuning = config.peers[0].tuning;
+    assert_eq!(tuning.mtu, 1300);
+    assert_eq!(tuning.tcp_buffer_size, 8192);
+    assert_eq!(tuning.persistent_keepalive, None);
+
+    let tuning = config.peers[1].tuning;
+    assert_eq!(tuning.mtu, 1280);
+    assert_eq!(tuning.tcp_buffer_size, 8192);
+    assert_eq!(tuning.persistent_keepalive, Some(10));
+}
+
+#[test]
+fn peers_can_turn_off_keepalive() {
+    let tables = format!(
+        "[[peers]]\nname = \"a\"\npublic_key = \"{}\"\npersistent_keepalive = 0\n\
+         [[peers]]\nname = \"b\"\npublic_key = \"{}\"\n{}",
+        PEER_KEY,
+        OTHER_PEER_KEY,
+        service("80", "localhost:8080")
+    );
+    let config = validate("", &tables).unwrap();
+
+    assert_eq!(config.peers[0].tuning.persistent_keepalive, None);
+    assert_eq!(config.peers[1].tuning.persistent_keepalive, Some(25));
+}
+
+#[test]
+fn peer_tuning_is_validated() {
+    let tables = format!(
+        "[[peers]]\nname = \"a\"\npublic_key = \"{}\"\nmtu = 4096\n{}",
+        PEER_KEY,
+        service("80", "localhost:8080")
+    );
+    let message = error(validate("", &tables));
+    assert!(message.contains("invalid `peers[0]`"), "{}", message);
+    assert!(message.contains("doesn't fit into `udp_buffer_size`"), "{}", message);
+}
+
+#[test]
+fn services_are_required() {
+    let message = error(validate("", ""));
+    assert!(message.contains("`services` must contain at least one service"), "{}", message);
+}
+
+#[test]
+fn duplicate_peers() {
+    let tables = format!(
+        "[[peers]]\nname = \"a\"\npublic_key = \"{}\"\n[[peers]]\nname = \"b\"\npublic_key = \"{}\"\n{}",
+        PEER_KEY,
+        PEER_KEY,
+        service("80", "localhost:8080")
+    );
+    let message = error(validate("", &tables));
+    assert!(message.contains("duplicate peer public key"), "{}", message);
+}
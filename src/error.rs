@@ -0,0 +1,29 @@
+use std::io;
+
+use smoltcp::socket::tcp::{ListenError, RecvError, SendError};
+use thiserror::Error;
+
+/// Failure of a single proxied connection, only that connection is reset.
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    #[error("failed to connect to upstream: {0}")]
+    Connect(io::Error),
+    #[error("forwarding failed: {0}")]
+    Forward(io::Error),
+    #[error("failed to listen on virtual socket: {0}")]
+    Listen(#[from] ListenError),
+    #[error("failed to receive from virtual socket: {0}")]
+    Recv(#[from] RecvError),
+    #[error("failed to send to virtual socket: {0}")]
+    Send(#[from] SendError),
+}
+
+/// Failure of a whole session, the session is dropped together with all of
+/// its connections.
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("failed to create tunnel: {0}")]
+    Tunnel(&'static str),
+    #[error("unexpected tunnel result while {0}")]
+    UnexpectedTunnelResult(&'static str),
+}
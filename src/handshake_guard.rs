@@ -0,0 +1,94 @@
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+use boringtun::noise::{rate_limiter::RateLimiter, Packet, TunnResult};
+
+/// Size of a wireguard cookie reply message.
+pub const COOKIE_REPLY_SIZE: usize = 64;
+
+/// What to do with a received datagram.
+pub enum Verdict<'a> {
+    Pass,
+    /// the server is under load and the sender has to prove its address first
+    CookieReply(&'a [u8]),
+    Drop,
+}
+
+/// Protects the server against floods of handshake messages, which cost a
+/// few X25519 operations each before it is even known who sent them.
+///
+/// Once the rate limiter counts too many handshake messages per second,
+/// only those with a valid mac2 are accepted and everyone else gets a cookie
+/// reply. On top of that at most `dh_budget` handshakes per second are
+/// processed at all.
+///
+/// The rate limiter is shared with the other workers and the tunnels of all
+/// sessions, so that the cookies handed out here are accepted everywhere.
+pub struct HandshakeGuard {
+    rate_limiter: Arc<RateLimiter>,
+
+    dh_budget: u32,
+    dh_used: u32,
+    dropped: u64,
+    last_reset: Instant,
+}
+
+impl HandshakeGuard {
+    pub fn new(rate_limiter: Arc<RateLimiter>, dh_budget: u32) -> Self {
+        HandshakeGuard {
+            rate_limiter,
+
+            dh_budget,
+            dh_used: 0,
+            dropped: 0,
+            last_reset: Instant::now(),
+        }
+    }
+
+    /// Has to be called with every datagram before anything else looks at it,
+    /// `cookie_buf` has to hold at least `COOKIE_REPLY_SIZE` bytes.
+    pub fn verify<'a>(
+        &mut self,
+        buf: &[u8],
+        remote: SocketAddr,
+        cookie_buf: &'a mut [u8],
+    ) -> Verdict<'a> {
+        self.reset();
+
+        match self
+            .rate_limiter
+            .verify_packet(Some(remote.ip()), buf, cookie_buf)
+        {
+            Ok(Packet::HandshakeInit(_)) | Ok(Packet::HandshakeResponse(_)) => {}
+            Ok(_) => return Verdict::Pass,
+            Err(TunnResult::WriteToNetwork(cookie)) => return Verdict::CookieReply(cookie),
+            // garbage or a handshake with an invalid mac1
+            Err(_) => return Verdict::Drop,
+        }
+
+        if self.dh_used >= self.dh_budget {
+            self.dropped += 1;
+            return Verdict::Drop;
+        }
+        self.dh_used += 1;
+
+        Verdict::Pass
+    }
+
+    fn reset(&mut self) {
+        self.rate_limiter.reset_count();
+
+        if self.last_reset.elapsed().as_secs() < 1 {
+            return;
+        }
+        if self.dropped > 0 {
+            println!(
+                "dropped {} handshakes over the budget of {} per second",
+                self.dropped, self.dh_budget
+            );
+        }
+
+        self.dh_used = 0;
+        self.dropped = 0;
+        self.last_reset = Instant::now();
+    }
+}
@@ -1,83 +1,88 @@
+pub mod config;
+pub mod error;
+pub mod handshake_guard;
 pub mod virtual_device;
+pub mod virtual_stack;
 pub mod session;
+pub mod shared_pool;
+pub mod tcp_forwarder;
+pub mod udp_batch;
+pub mod udp_listener;
 pub mod wireguard_helper;
 pub mod virtual_tcp_socket;
+pub mod wakeup;
+pub mod worker;
 
-use std::{
-    net::{IpAddr, SocketAddr},
-    time::Duration, sync::Arc,
-};
+use std::{path::PathBuf, sync::Arc};
 
-use boringtun::x25519::StaticSecret;
+use anyhow::bail;
+use boringtun::{
+    noise::rate_limiter::RateLimiter,
+    x25519::{PublicKey, StaticSecret},
+};
+use config::Config;
+use handshake_guard::HandshakeGuard;
 use hashbrown::HashMap;
-use session::Session;
-use wireguard_helper::parse_key;
-
-use crate::wireguard_helper::{extract_handshake, print_key};
+use udp_listener::UdpListener;
+use worker::{Shared, Worker};
 
-#[tokio::main(flavor = "current_thread")]
+#[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let bind_ip_port: SocketAddr = "0.0.0.0:51821".parse()?;
-    // TODO: don't use that key!
-    let private_key: StaticSecret =
-        parse_key("sNLSbiLbh1NzkGeoQmeVxy3YJHMlJ+6WdkggInPgN0k=")?.into();
-
-    let internal_address: IpAddr = "192.168.222.11".parse()?;
-
-    let udp = tokio::net::UdpSocket::bind(bind_ip_port).await?;
-    let udp = Arc::new(udp);
-
-    let mut poll_wireguard_stack = tokio::time::interval(Duration::from_secs(1));
-    let mut poll_internal_stack = tokio::time::interval(Duration::from_millis(10));
-
-    let mut udp_recv_buf = [0; 4096 - 32];
-
-    let mut connections: HashMap<SocketAddr, Session> = HashMap::new();
-
-    loop {
-        tokio::select! {
-            _ = poll_wireguard_stack.tick() => {
-                for (_remote, session) in &mut connections {
-                    session.process_wireguard_timer().await;
-                    session.send_udp().await;
-                }
-            }
-
-            _ = poll_internal_stack.tick() => {
-                for (_remote, session) in &mut connections {
-                    session.poll_stack();
-                    session.send_udp().await;
-
-                    session.handle_tcp().unwrap();
-                }
-            }
-
-            ret = udp.recv_from(&mut udp_recv_buf) => {
-                let (size, remote) = ret?;
-                let buf : &[u8] = &udp_recv_buf[0..size];
+    let Some(config_path) = std::env::args_os().nth(1) else {
+        bail!("usage: wireguard-reverse-proxy <config.toml>");
+    };
+    let config = Config::load(&PathBuf::from(config_path))?;
+
+    let mut peers = HashMap::new();
+    for peer in config.peers {
+        let allowed_ips: Vec<String> = peer.allowed_ips.iter().map(|cidr| cidr.to_string()).collect();
+        println!("peer {} allowed ips: {}", peer.name, allowed_ips.join(", "));
+        peers.insert(peer.public_key, peer);
+    }
 
-                match connections.entry(remote) {
-                    hashbrown::hash_map::Entry::Occupied(entry) => {
-                        let session = entry.into_mut();
-                        session.process_wireguard(buf, &udp, remote).await;
-                        session.send_udp().await;
-                    },
-                    hashbrown::hash_map::Entry::Vacant(entry) => {
-                        if let Some(handshake) = extract_handshake(&private_key, buf) {
-                            println!("new session...");
-                            print_key(handshake.peer_static_public);
-                            // TODO: check if we want to handle that peer...
+    let private_key = StaticSecret::from(config.private_key);
+    // every handshake message passes the limiter twice, in the handshake guard
+    // of its worker and in the tunnel of its session
+    let rate_limiter = RateLimiter::new(&PublicKey::from(&private_key), config.handshake_rate_limit * 2);
+
+    let shared = Arc::new(Shared {
+        private_key,
+        rate_limiter: Arc::new(rate_limiter),
+        peers,
+        internal_addresses: config.addresses,
+        services: config.services.into(),
+        session_timeout: config.session_timeout,
+        udp_buffer_size: config.udp_buffer_size,
+    });
+
+    // the first listener resolves port 0, the others bind to the same addresses
+    let reuse_port = config.workers > 1;
+    let mut udp_listeners = vec![UdpListener::bind(&config.listen, reuse_port)?];
+    let listen = udp_listeners[0].local_addrs()?;
+    for _ in 1..config.workers {
+        udp_listeners.push(UdpListener::bind(&listen, reuse_port)?);
+    }
+    for address in &listen {
+        println!("listening on {}", address);
+    }
+    if config.workers > 1 {
+        println!("running {} workers", config.workers);
+    }
 
-                            let mut session = Session::new(&private_key, handshake.peer_static_public.into(), internal_address, udp.clone(), remote)?;
+    // each worker only sees its share of the handshakes
+    let handshake_budget = config.handshake_budget.div_ceil(config.workers as u32);
 
-                            session.process_wireguard(buf, &udp, remote).await;
-                            session.send_udp().await;
+    let (inboxes, receivers) = worker::inboxes(config.workers);
+    let mut tasks = Vec::with_capacity(config.workers);
+    for (id, (udp_listener, inbox)) in udp_listeners.into_iter().zip(receivers).enumerate() {
+        let handshake_guard = HandshakeGuard::new(shared.rate_limiter.clone(), handshake_budget);
+        let worker = Worker::new(id, shared.clone(), udp_listener, handshake_guard, inboxes.clone(), inbox);
+        tasks.push(tokio::spawn(worker.run()));
+    }
 
-                            entry.insert(session);
-                         };
-                    },
-                }
-            }
-        };
+    for task in tasks {
+        task.await?;
     }
+
+    Ok(())
 }
@@ -1,189 +1,183 @@
 use std::{
     net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 
 use boringtun::{
-    noise::Tunn,
+    noise::{rate_limiter::RateLimiter, Packet, Tunn},
     x25519::{PublicKey, StaticSecret},
 };
-use smoltcp::{
-    iface::{Config, Interface, SocketHandle, SocketSet, SocketStorage},
-    socket::tcp::{self, Socket},
-    time::Instant,
-    wire::{HardwareAddress, IpCidr, IpEndpoint},
-};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, UdpSocket},
-    select, spawn,
+    net::UdpSocket,
+    select,
+    sync::mpsc,
+    time::{interval, sleep_until, Instant},
 };
 
 use crate::{
-    virtual_device::VirtualDevice,
-    virtual_tcp_socket::{VirtualTcpSocket, VirtualTcpSocketSyncSide},
+    config::{PeerConfig, ServiceConfig},
+    error::SessionError,
+    shared_pool::PooledBuffer,
+    udp_batch::SendBatch,
+    virtual_stack::VirtualStack,
+    wakeup::Wakeup,
 };
 
-pub struct Session<'a> {
-    tunn: Tunn,
-    wg_buffer: [u8; 4096],
+/// Boringtun expects its timers to be updated about that often.
+const WIREGUARD_TIMER_INTERVAL: Duration = Duration::from_millis(250);
 
-    interface: Interface,
-    device: VirtualDevice,
+/// A datagram the dispatcher routed to a session.
+pub struct Datagram {
+    pub data: PooledBuffer,
+    pub remote: SocketAddr,
+    /// socket the datagram arrived on
+    pub udp: Arc<UdpSocket>,
+}
 
-    sockets: SocketSet<'a>,
+/// Whether decapsulating `packet` proved that it came from the peer. A cookie
+/// reply doesn't, the mac1 it answers only needs our public key.
+fn is_authenticated(packet: &[u8], result: &boringtun::noise::TunnResult) -> bool {
+    match (Tunn::parse_incoming_packet(packet), result) {
+        // keepalives decrypt to nothing
+        (
+            Ok(Packet::PacketData(_)),
+            boringtun::noise::TunnResult::WriteToTunnelV4(_, _)
+            | boringtun::noise::TunnResult::WriteToTunnelV6(_, _)
+            | boringtun::noise::TunnResult::Done,
+        ) => true,
+        // a processed handshake is answered with a response or a keepalive
+        (
+            Ok(Packet::HandshakeInit(_) | Packet::HandshakeResponse(_)),
+            boringtun::noise::TunnResult::WriteToNetwork(reply),
+        ) => !matches!(
+            Tunn::parse_incoming_packet(reply),
+            Ok(Packet::PacketCookieReply(_))
+        ),
+        _ => false,
+    }
+}
 
-    tcp_socket: SocketHandle,
+/// A peer's tunnel and stack, run as its own task by `run`.
+pub struct Session<'a> {
+    index: u32,
+    tunn: Tunn,
+    wg_buffer: Vec<u8>,
+    /// encapsulated packets waiting to be sent together
+    send_batch: SendBatch,
 
-    virtual_tcp_socket_sync: VirtualTcpSocketSyncSide,
+    stack: VirtualStack<'a>,
+    wakeup: Wakeup,
+    /// when the stack wants to be polled next
+    next_poll: Option<Instant>,
 
     udp: Arc<UdpSocket>,
     peer_address: SocketAddr,
-}
-
-fn create_tcp_socket<'a>() -> Socket<'a> {
-    let buffer_size = 65535;
 
-    let rx_buffer = tcp::SocketBuffer::new(vec![0; buffer_size]);
-    let tx_buffer = tcp::SocketBuffer::new(vec![0; buffer_size]);
-
-    return tcp::Socket::new(rx_buffer, tx_buffer);
+    peer_name: String,
+    /// time of the last completed handshake, or of the session creation
+    last_handshake: Instant,
 }
 
-impl Session<'_> {
+impl Session<'static> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         private_key: &StaticSecret,
-        peer_static_public: PublicKey,
-        internal_address: IpAddr,
+        peer: &PeerConfig,
+        index: u32,
+        internal_addresses: &[IpAddr],
+        services: Arc<[ServiceConfig]>,
         udp: Arc<UdpSocket>,
         peer_address: SocketAddr,
-    ) -> anyhow::Result<Self> {
-        let wg_buffer: [u8; 4096] = [0; 4096];
-        let persistent_keepalive = Some(25);
+        udp_buffer_size: usize,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> Result<Self, SessionError> {
+        // has to hold everything that fits into a received datagram
+        let wg_buffer: Vec<u8> = vec![0; udp_buffer_size];
 
         let tunn: Tunn = boringtun::noise::Tunn::new(
             private_key.clone(),
-            peer_static_public,
-            None,
-            persistent_keepalive,
-            0,
-            None,
+            PublicKey::from(peer.public_key),
+            peer.preshared_key,
+            peer.tuning.persistent_keepalive,
+            index,
+            // resetting its count is up to the handshake guard
+            Some(rate_limiter),
         )
-        .map_err(|e| anyhow::anyhow!(e))?;
-
-        let mut sockets = SocketSet::new([SocketStorage::EMPTY; 2]);
-
-        let mut tcp_socket = create_tcp_socket();
-        tcp_socket
-            .listen(IpEndpoint::new(internal_address.into(), 80))
-            .map_err(|e| anyhow::anyhow!(e))?;
-
-        // tcp_socket.state()
-
-        let tcp_socket = sockets.add(tcp_socket);
-
-        let mut device = VirtualDevice::new();
-        let mut interface = Interface::new(
-            Config::new(HardwareAddress::Ip),
-            &mut device,
-            Instant::now(),
-        );
-
-        interface.update_ip_addrs(|addresses| {
-            let _ = addresses.push(IpCidr::new(internal_address.into(), 0));
-        });
-
-        let (virtual_tcp_socket_sync, mut virtual_tcp_socket_async) = VirtualTcpSocket::new();
-
-        spawn(async move {
-            // virtual_tcp_socket_async
-
-            // TODO: maybe it could be a good idea to split sending+receving in two different tasks
-            // that way one full buffer could not block the other one
+        .map_err(SessionError::Tunnel)?;
 
-            let target_addr: SocketAddr = "127.0.0.1:80".parse().unwrap();
-
-            println!("connecting");
-
-            let Ok(mut tcp_stream) = TcpStream::connect(target_addr).await else {
-                // virtual_tcp_socket_async.close();
-                println!("failed to connect to tcp end...");
-
-                virtual_tcp_socket_async.close().await;
-                return;
-            };
-
-            let mut buf_a: [u8; 4096] = [0; 4096];
-            let mut buf_b: [u8; 4096] = [0; 4096];
-
-            loop {
-                select! {
-                    r = virtual_tcp_socket_async.read(& mut buf_a) => {
-                        match r {
-                            Ok(size) => {
-                                println!("received {} from virtual", size);
-                                match tcp_stream.write_all(& mut buf_a[..size]).await {
-                                    Ok(_) => {
-                                        println!("written to physical...");
-                                    },
-                                    Err(_) => todo!(),
-                                }
-                            },
-                            Err(_) => todo!(),
-                        }
-                    },
-                    r = tcp_stream.read(&mut buf_b) => {
-                        match r {
-                            Ok(0) => {
-                                println!("closed by real tcp");
-                                break;
-                            }
-                            Ok(size) => {
-                                println!("received {} from real", size);
-                                match virtual_tcp_socket_async.write_all(& mut buf_b[0..size]).await {
-                                    Ok(_) => {
-                                        println!("written to virtual...");
-                                    },
-                                    Err(_) => todo!(),
-                                }
-                            },
-                            Err(_) => todo!(),
-                        }
-                    }
-                }
-            }
-        });
+        let wakeup = Wakeup::default();
 
         Ok(Session {
+            index,
             tunn,
             wg_buffer,
-            interface,
-            device,
-            sockets,
+            send_batch: SendBatch::default(),
 
-            tcp_socket,
-
-            virtual_tcp_socket_sync,
+            stack: VirtualStack::new(peer, internal_addresses, services, udp_buffer_size, wakeup.clone()),
+            wakeup,
+            next_poll: None,
 
             udp,
             peer_address,
+
+            peer_name: peer.name.clone(),
+            last_handshake: Instant::now(),
         })
     }
 
-    pub async fn process_wireguard(
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn peer_name(&self) -> &str {
+        &self.peer_name
+    }
+
+    /// A session expires when it had no valid handshake for `timeout`.
+    fn check_expired(&mut self, timeout: Duration) -> bool {
+        // boringtun forgets about the handshake once the keys are rejected
+        if let Some(since) = self.tunn.time_since_last_handshake() {
+            if let Some(last_handshake) = Instant::now().checked_sub(since) {
+                self.last_handshake = last_handshake;
+            }
+        }
+
+        self.last_handshake.elapsed() > timeout
+    }
+
+    async fn process_wireguard(
         &mut self,
         buf: &[u8],
-        udp: &tokio::net::UdpSocket,
+        udp: &Arc<UdpSocket>,
         peer_address: SocketAddr,
     ) {
         let mut buf = buf;
         loop {
-            match self.tunn.decapsulate(None, buf, &mut self.wg_buffer) {
+            // packets for the stack are decapsulated right into its receive buffer
+            let result = self
+                .tunn
+                .decapsulate(Some(peer_address.ip()), buf, self.stack.receive_buffer());
+
+            // like kernel wireguard, replies go to the endpoint the last authenticated
+            // packet came from, through the socket it arrived on
+            if !buf.is_empty() && is_authenticated(buf, &result) {
+                if self.peer_address != peer_address {
+                    println!(
+                        "peer {} roamed from {} to {}",
+                        self.peer_name, self.peer_address, peer_address
+                    );
+                }
+                self.udp = udp.clone();
+                self.peer_address = peer_address;
+            }
+
+            match result {
                 boringtun::noise::TunnResult::Done => {
                     return;
                 }
                 boringtun::noise::TunnResult::Err(e) => {
-                    print!("wireguard error: {:?}", e)
+                    println!("wireguard error: {:?}", e)
                 }
                 boringtun::noise::TunnResult::WriteToNetwork(buf) => {
                     match udp.send_to(buf, peer_address).await {
@@ -194,24 +188,27 @@ impl Session<'_> {
                     }
                 }
 
-                boringtun::noise::TunnResult::WriteToTunnelV4(buf, _) => {
-                    self.device.add_received(buf);
-                    self.poll_stack();
+                boringtun::noise::TunnResult::WriteToTunnelV4(packet, source) => {
+                    let size = packet.len();
+                    self.stack.receive(size, source.into());
+                }
+                boringtun::noise::TunnResult::WriteToTunnelV6(packet, source) => {
+                    let size = packet.len();
+                    self.stack.receive(size, source.into());
                 }
-                boringtun::noise::TunnResult::WriteToTunnelV6(_, _) => todo!(),
             }
 
             buf = b"";
         }
     }
 
-    pub async fn process_wireguard_timer(&mut self) {
+    async fn process_wireguard_timer(&mut self) -> Result<(), SessionError> {
         match self.tunn.update_timers(&mut self.wg_buffer) {
             boringtun::noise::TunnResult::Done => {
-                return;
+                return Ok(());
             }
             boringtun::noise::TunnResult::Err(e) => {
-                print!("wireguard error: {:?}", e)
+                println!("wireguard error: {:?}", e)
             }
             boringtun::noise::TunnResult::WriteToNetwork(buf) => {
                 match self.udp.send_to(buf, self.peer_address).await {
@@ -222,17 +219,19 @@ impl Session<'_> {
                 }
             }
 
-            boringtun::noise::TunnResult::WriteToTunnelV4(_, _) => unreachable!(),
-            boringtun::noise::TunnResult::WriteToTunnelV6(_, _) => unreachable!(),
+            boringtun::noise::TunnResult::WriteToTunnelV4(_, _)
+            | boringtun::noise::TunnResult::WriteToTunnelV6(_, _) => {
+                return Err(SessionError::UnexpectedTunnelResult("updating timers"));
+            }
         }
 
         loop {
-            match self.tunn.decapsulate(None, b"", &mut self.wg_buffer) {
+            match self.tunn.decapsulate(None, b"", self.stack.receive_buffer()) {
                 boringtun::noise::TunnResult::Done => {
-                    return;
+                    return Ok(());
                 }
                 boringtun::noise::TunnResult::Err(e) => {
-                    print!("wireguard error: {:?}", e)
+                    println!("wireguard error: {:?}", e)
                 }
                 boringtun::noise::TunnResult::WriteToNetwork(buf) => {
                     match self.udp.send_to(buf, self.peer_address).await {
@@ -243,50 +242,122 @@ impl Session<'_> {
                     }
                 }
 
-                boringtun::noise::TunnResult::WriteToTunnelV4(buf, _) => {
-                    self.device.add_received(buf);
-                    self.poll_stack();
+                // packets which were queued until the handshake completed
+                boringtun::noise::TunnResult::WriteToTunnelV4(packet, source) => {
+                    let size = packet.len();
+                    self.stack.receive(size, source.into());
+                    self.wakeup.wake();
+                }
+                boringtun::noise::TunnResult::WriteToTunnelV6(packet, source) => {
+                    let size = packet.len();
+                    self.stack.receive(size, source.into());
+                    self.wakeup.wake();
                 }
-                boringtun::noise::TunnResult::WriteToTunnelV6(_, _) => todo!(),
             }
         }
     }
 
-    pub async fn send_udp(&mut self) {
-        loop {
-            let Some(packet) = self.device.get_for_sending() else {
-                break;
-            };
-            match self.tunn.encapsulate(&packet, &mut self.wg_buffer) {
-                boringtun::noise::TunnResult::Done => return,
+    async fn send_udp(&mut self) -> Result<(), SessionError> {
+        let result = self.encapsulate_all().await;
+
+        // the packets encapsulated before an error are still sent
+        if let Err(e) = self.send_batch.flush(&self.udp, self.peer_address).await {
+            println!("failed to send packet to peer: {:?}", e);
+        }
+
+        result
+    }
+
+    /// Encapsulates the packets of the stack into the send batch, which is
+    /// flushed whenever it is full.
+    async fn encapsulate_all(&mut self) -> Result<(), SessionError> {
+        while let Some(packet) = self.stack.get_for_sending() {
+            // at most as large as the largest datagram a peer may send
+            let buf = self.send_batch.reserve(self.wg_buffer.len());
+            let mut queued = false;
+            match self.tunn.encapsulate(&packet, buf) {
+                // boringtun keeps the packet until the handshake is done
+                boringtun::noise::TunnResult::Done => queued = true,
                 boringtun::noise::TunnResult::Err(e) => {
-                    print!("wireguard error: {:?}", e)
+                    println!("wireguard error: {:?}", e)
                 }
                 boringtun::noise::TunnResult::WriteToNetwork(buf) => {
-                    match self.udp.send_to(buf, self.peer_address).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("failed to send packet to peer: ${:?}", e);
-                        }
-                    }
+                    let size = buf.len();
+                    self.send_batch.commit(size);
                 }
 
-                boringtun::noise::TunnResult::WriteToTunnelV4(_, _) => todo!(),
-                boringtun::noise::TunnResult::WriteToTunnelV6(_, _) => todo!(),
+                boringtun::noise::TunnResult::WriteToTunnelV4(_, _)
+                | boringtun::noise::TunnResult::WriteToTunnelV6(_, _) => {
+                    return Err(SessionError::UnexpectedTunnelResult("encapsulating"));
+                }
+            }
+
+            // the packet was copied, its buffer can be reused
+            self.stack.recycle(packet);
+            if queued {
+                return Ok(());
+            }
+
+            if self.send_batch.is_full() {
+                if let Err(e) = self.send_batch.flush(&self.udp, self.peer_address).await {
+                    println!("failed to send packet to peer: {:?}", e);
+                }
             }
         }
+
+        Ok(())
     }
 
-    pub fn poll_stack(&mut self) {
-        self.interface
-            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+    /// Moves data between the virtual sockets and their upstreams and sends
+    /// whatever the stack has to send.
+    async fn drive(&mut self) -> Result<(), SessionError> {
+        self.stack.handle_tcp();
+        self.stack.poll();
+        self.send_udp().await?;
+
+        self.next_poll = self
+            .stack
+            .poll_delay()
+            .map(|delay| Instant::now() + delay);
+
+        Ok(())
     }
 
-    pub fn handle_tcp(&mut self) -> anyhow::Result<()> {
-        let tcp_socket = self.sockets.get_mut::<tcp::Socket>(self.tcp_socket);
+    /// Runs the session until it expires or fails, or the dispatcher is gone.
+    pub async fn run(
+        mut self,
+        mut datagrams: mpsc::Receiver<Datagram>,
+        session_timeout: Duration,
+    ) -> Result<(), SessionError> {
+        let mut wireguard_timer = interval(WIREGUARD_TIMER_INTERVAL);
 
-        self.virtual_tcp_socket_sync.process(tcp_socket);
+        loop {
+            let next_poll = self.next_poll;
+
+            select! {
+                datagram = datagrams.recv() => {
+                    let Some(datagram) = datagram else {
+                        return Ok(());
+                    };
+                    self.process_wireguard(&datagram.data, &datagram.udp, datagram.remote).await;
+                }
+
+                _ = self.wakeup.woken() => {}
+
+                _ = sleep_until(next_poll.unwrap_or_else(Instant::now)), if next_poll.is_some() => {}
 
-        return Ok(());
+                _ = wireguard_timer.tick() => {
+                    self.process_wireguard_timer().await?;
+
+                    // dropping the session resets the upstreams of its connections
+                    if self.check_expired(session_timeout) {
+                        println!("session of peer {} expired", self.peer_name);
+                        return Ok(());
+                    }
+                }
+            }
+
+            self.drive().await?;
+        }
     }
 }
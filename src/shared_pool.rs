@@ -0,0 +1,66 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+/// Reuses buffers which are handed from one task to another, e.g. through a
+/// channel, so that forwarding doesn't allocate once it is warmed up.
+#[derive(Clone)]
+pub struct SharedPool {
+    free: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// buffers kept for reuse at most, the others are freed
+    limit: usize,
+}
+
+impl SharedPool {
+    pub fn new(limit: usize) -> Self {
+        SharedPool {
+            free: Arc::new(Mutex::new(Vec::new())),
+            limit,
+        }
+    }
+
+    /// An empty buffer, which goes back to the pool when it is dropped.
+    pub fn take(&self) -> PooledBuffer {
+        let buffer = self.free.lock().unwrap().pop().unwrap_or_default();
+        PooledBuffer {
+            buffer,
+            pool: self.clone(),
+        }
+    }
+
+    fn recycle(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.limit {
+            free.push(buffer);
+        }
+    }
+}
+
+/// A buffer of a `SharedPool`, it keeps the capacity it grew to.
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: SharedPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.recycle(std::mem::take(&mut self.buffer));
+    }
+}
@@ -0,0 +1,395 @@
+use std::{io, net::IpAddr, sync::Arc, time::Duration};
+
+use smoltcp::{
+    iface::{SocketHandle, SocketSet},
+    socket::tcp::{self, Socket},
+    wire::{IpAddress, IpEndpoint, IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket},
+};
+use tokio::{
+    io::copy_bidirectional,
+    net::TcpStream,
+    spawn,
+    sync::oneshot::{self, error::TryRecvError},
+    time::timeout,
+};
+
+use crate::{
+    config::ServiceConfig,
+    error::ConnectionError,
+    shared_pool::SharedPool,
+    virtual_tcp_socket::{VirtualTcpSocket, VirtualTcpSocketAsyncSide, VirtualTcpSocketSyncSide},
+    wakeup::Wakeup,
+};
+
+/// Upper bound of virtual tcp sockets (listening and connected) and held back
+/// SYNs per session.
+const MAX_TCP_SOCKETS: usize = 64;
+
+/// How long a SYN is held back for services which connect to the upstream
+/// first, and how long the peer has to complete the handshake after that.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Chunks of the connections of a session kept for reuse.
+const CHUNK_POOL_SIZE: usize = 64;
+
+struct TcpConnection {
+    handle: SocketHandle,
+    remote: IpEndpoint,
+    local_port: u16,
+    upstream: String,
+    /// already connected upstream of services which connect first
+    upstream_stream: Option<TcpStream>,
+    /// only set once the connection is established and the upstream is dialed
+    virtual_tcp_socket_sync: Option<VirtualTcpSocketSyncSide>,
+}
+
+/// A SYN which is held back until its upstream is connected.
+struct PendingConnection {
+    remote: IpEndpoint,
+    local: IpEndpoint,
+    upstream: String,
+    syn: Vec<u8>,
+    connected: oneshot::Receiver<io::Result<TcpStream>>,
+}
+
+/// Owns the virtual tcp sockets of a session and forwards the connections
+/// to the upstreams of the configured services.
+pub struct TcpForwarder<'a> {
+    addresses: Vec<IpAddress>,
+    services: Arc<[ServiceConfig]>,
+    tcp_buffer_size: usize,
+    wakeup: Wakeup,
+    chunk_pool: SharedPool,
+
+    sockets: SocketSet<'a>,
+    connections: Vec<TcpConnection>,
+    pending_connections: Vec<PendingConnection>,
+}
+
+fn create_tcp_socket<'a>(buffer_size: usize) -> Socket<'a> {
+    let rx_buffer = tcp::SocketBuffer::new(vec![0; buffer_size]);
+    let tx_buffer = tcp::SocketBuffer::new(vec![0; buffer_size]);
+
+    tcp::Socket::new(rx_buffer, tx_buffer)
+}
+
+/// Returns source and destination of a tcp packet which opens a connection.
+fn parse_syn(packet: &[u8]) -> Option<(IpEndpoint, IpEndpoint)> {
+    let (src_addr, dst_addr, payload): (IpAddress, IpAddress, &[u8]) =
+        match IpVersion::of_packet(packet).ok()? {
+            IpVersion::Ipv4 => {
+                let ip = Ipv4Packet::new_checked(packet).ok()?;
+                if ip.next_header() != IpProtocol::Tcp {
+                    return None;
+                }
+                (ip.src_addr().into(), ip.dst_addr().into(), ip.payload())
+            }
+            // extension headers are not supported by the stack either
+            IpVersion::Ipv6 => {
+                let ip = Ipv6Packet::new_checked(packet).ok()?;
+                if ip.next_header() != IpProtocol::Tcp {
+                    return None;
+                }
+                (ip.src_addr().into(), ip.dst_addr().into(), ip.payload())
+            }
+        };
+
+    let tcp = TcpPacket::new_checked(payload).ok()?;
+    if !tcp.syn() || tcp.ack() {
+        return None;
+    }
+
+    Some((
+        IpEndpoint::new(src_addr, tcp.src_port()),
+        IpEndpoint::new(dst_addr, tcp.dst_port()),
+    ))
+}
+
+/// Connects to the upstream and shovels data between it and the virtual tcp socket.
+///
+/// Dropping the async side at the end closes the virtual socket once all data
+/// written to it has been sent, or resets it when forwarding failed.
+fn spawn_upstream(
+    upstream: String,
+    upstream_stream: Option<TcpStream>,
+    mut virtual_tcp_socket_async: VirtualTcpSocketAsyncSide,
+) {
+    spawn(async move {
+        if let Err(e) =
+            forward_upstream(&upstream, upstream_stream, &mut virtual_tcp_socket_async).await
+        {
+            println!("connection to {} failed: {}", upstream, e);
+            virtual_tcp_socket_async.reset();
+        }
+    });
+}
+
+async fn forward_upstream(
+    upstream: &str,
+    upstream_stream: Option<TcpStream>,
+    virtual_tcp_socket_async: &mut VirtualTcpSocketAsyncSide,
+) -> Result<(), ConnectionError> {
+    let mut tcp_stream = match upstream_stream {
+        Some(tcp_stream) => tcp_stream,
+        None => {
+            println!("connecting to {}", upstream);
+            TcpStream::connect(upstream)
+                .await
+                .map_err(ConnectionError::Connect)?
+        }
+    };
+
+    // an EOF on one side shuts down writing on the other one, both directions
+    // are forwarded until they are done
+    // the peer may reset the connection even after the upstream read its EOF
+    let reset_signal = virtual_tcp_socket_async.reset_signal();
+    let result = tokio::select! {
+        result = copy_bidirectional(&mut tcp_stream, virtual_tcp_socket_async) => result,
+        _ = reset_signal.reset() => Err(io::ErrorKind::ConnectionReset.into()),
+    };
+
+    let (received, sent) = match result {
+        Ok(transferred) => transferred,
+        Err(e) => {
+            reset_on_drop(&tcp_stream);
+            return Err(ConnectionError::Forward(e));
+        }
+    };
+    println!(
+        "connection to {} closed, {} bytes sent and {} bytes received",
+        upstream, sent, received
+    );
+
+    Ok(())
+}
+
+/// Without lingering the stream is reset when it is dropped.
+fn reset_on_drop(tcp_stream: &TcpStream) {
+    let _ = socket2::SockRef::from(tcp_stream).set_linger(Some(Duration::ZERO));
+}
+
+impl<'a> TcpForwarder<'a> {
+    pub fn new(
+        addresses: &[IpAddr],
+        services: Arc<[ServiceConfig]>,
+        tcp_buffer_size: usize,
+        wakeup: Wakeup,
+    ) -> Self {
+        TcpForwarder {
+            addresses: addresses.iter().map(|address| (*address).into()).collect(),
+            services,
+            tcp_buffer_size,
+            wakeup,
+            chunk_pool: SharedPool::new(CHUNK_POOL_SIZE),
+
+            sockets: SocketSet::new(vec![]),
+            connections: Vec::new(),
+            pending_connections: Vec::new(),
+        }
+    }
+
+    pub fn sockets(&mut self) -> &mut SocketSet<'a> {
+        &mut self.sockets
+    }
+
+    /// Has to be called with every packet before it is handed to the stack,
+    /// returns false if the packet is held back.
+    ///
+    /// A SYN to the port of a service gets a fresh socket in LISTEN for it, SYNs
+    /// to any other port are answered with a RST by the stack. For services
+    /// which connect first the SYN is only handed to the stack once the
+    /// upstream is connected, see `resolve_pending`.
+    pub fn prepare_listener(&mut self, packet: &[u8]) -> Result<bool, ConnectionError> {
+        let Some((remote, local)) = parse_syn(packet) else {
+            return Ok(true);
+        };
+        if !self.addresses.contains(&local.addr) {
+            return Ok(true);
+        }
+
+        // retransmitted SYNs belong to the connection which is already there
+        if self
+            .connections
+            .iter()
+            .any(|c| c.remote == remote && c.local_port == local.port)
+        {
+            return Ok(true);
+        }
+        if self
+            .pending_connections
+            .iter()
+            .any(|p| p.remote == remote && p.local == local)
+        {
+            return Ok(false);
+        }
+
+        let Some(service) = self.services.iter().find(|s| s.ports.contains(&local.port)) else {
+            return Ok(true);
+        };
+
+        if self.connections.len() + self.pending_connections.len() >= MAX_TCP_SOCKETS {
+            println!("too many tcp connections, refusing {}", remote);
+            return Ok(true);
+        }
+
+        let upstream = service.upstream_for(local.port);
+        if !service.connect_first {
+            self.listen(remote, local, upstream, None)?;
+            return Ok(true);
+        }
+
+        let (connected_sender, connected) = oneshot::channel();
+        let target = upstream.clone();
+        let wakeup = self.wakeup.clone();
+        spawn(async move {
+            println!("connecting to {}", target);
+            let result = match timeout(CONNECT_TIMEOUT, TcpStream::connect(&target)).await {
+                Ok(result) => result,
+                Err(_) => Err(io::ErrorKind::TimedOut.into()),
+            };
+            let _ = connected_sender.send(result);
+            wakeup.wake();
+        });
+
+        self.pending_connections.push(PendingConnection {
+            remote,
+            local,
+            upstream,
+            syn: packet.to_vec(),
+            connected,
+        });
+
+        Ok(false)
+    }
+
+    fn listen(
+        &mut self,
+        remote: IpEndpoint,
+        local: IpEndpoint,
+        upstream: String,
+        upstream_stream: Option<TcpStream>,
+    ) -> Result<(), ConnectionError> {
+        let mut tcp_socket = create_tcp_socket(self.tcp_buffer_size);
+        tcp_socket.listen(local)?;
+        // a peer which never acknowledges the SYN-ACK doesn't get to keep the socket
+        tcp_socket.set_timeout(Some(CONNECT_TIMEOUT.into()));
+
+        self.connections.push(TcpConnection {
+            handle: self.sockets.add(tcp_socket),
+            remote,
+            local_port: local.port,
+            upstream,
+            upstream_stream,
+            virtual_tcp_socket_sync: None,
+        });
+
+        Ok(())
+    }
+
+    /// Returns the held back SYNs whose upstream dial finished, they have to
+    /// be handed to the stack before it is polled next. Those whose upstream
+    /// failed find no listener and are answered with a RST.
+    pub fn resolve_pending(&mut self) -> Vec<Vec<u8>> {
+        let mut syns = Vec::new();
+
+        let mut i = 0;
+        while i < self.pending_connections.len() {
+            let result = match self.pending_connections[i].connected.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => {
+                    i += 1;
+                    continue;
+                }
+                Err(TryRecvError::Closed) => Err(io::ErrorKind::Interrupted.into()),
+            };
+
+            let pending = self.pending_connections.swap_remove(i);
+            match result {
+                Ok(upstream_stream) => {
+                    if let Err(e) = self.listen(
+                        pending.remote,
+                        pending.local,
+                        pending.upstream,
+                        Some(upstream_stream),
+                    ) {
+                        println!("failed to listen for {}: {:?}", pending.remote, e);
+                    }
+                }
+                Err(e) => {
+                    println!(
+                        "refusing {}, failed to connect to {}: {}",
+                        pending.remote, pending.upstream, e
+                    );
+                }
+            }
+            syns.push(pending.syn);
+        }
+
+        syns
+    }
+
+    /// Moves data between the virtual sockets and their upstreams.
+    pub fn process(&mut self) {
+        for connection in &mut self.connections {
+            let Some(virtual_tcp_socket_sync) = &mut connection.virtual_tcp_socket_sync else {
+                continue;
+            };
+            let tcp_socket = self.sockets.get_mut::<tcp::Socket>(connection.handle);
+
+            if let Err(e) = virtual_tcp_socket_sync.process(tcp_socket) {
+                // the upstream task ends once the sync side is gone
+                println!("resetting connection from {}: {}", connection.remote, e);
+                tcp_socket.abort();
+                virtual_tcp_socket_sync.reset();
+                connection.virtual_tcp_socket_sync = None;
+            }
+        }
+    }
+
+    /// Has to be called after every poll of the stack, dials the upstream for
+    /// newly established connections and frees the sockets of finished ones.
+    pub fn update(&mut self) {
+        let sockets = &mut self.sockets;
+        self.connections.retain_mut(|connection| {
+            let tcp_socket = sockets.get_mut::<tcp::Socket>(connection.handle);
+            match tcp_socket.state() {
+                // an aborted socket still has to send its RST
+                tcp::State::Closed if tcp_socket.remote_endpoint().is_some() => true,
+                // a listener which didn't take its SYN or got reset during the handshake,
+                // or a connection which is done
+                tcp::State::Closed | tcp::State::Listen => {
+                    // dropping the sync side resets the upstream unless both sides were done
+                    if let Some(upstream_stream) = connection.upstream_stream.take() {
+                        println!(
+                            "dropping connection to {}, {} didn't complete the handshake",
+                            connection.upstream, connection.remote
+                        );
+                        reset_on_drop(&upstream_stream);
+                    }
+
+                    sockets.remove(connection.handle);
+                    false
+                }
+                // the peer may already have sent its FIN along with the first data
+                tcp::State::Established | tcp::State::CloseWait
+                    if connection.virtual_tcp_socket_sync.is_none() =>
+                {
+                    // established connections may stall for as long as they like
+                    tcp_socket.set_timeout(None);
+
+                    let (virtual_tcp_socket_sync, virtual_tcp_socket_async) =
+                        VirtualTcpSocket::new(self.wakeup.clone(), self.chunk_pool.clone());
+                    spawn_upstream(
+                        connection.upstream.clone(),
+                        connection.upstream_stream.take(),
+                        virtual_tcp_socket_async,
+                    );
+
+                    connection.virtual_tcp_socket_sync = Some(virtual_tcp_socket_sync);
+                    true
+                }
+                _ => true,
+            }
+        });
+    }
+}
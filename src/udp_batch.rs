@@ -0,0 +1,381 @@
+use std::{io, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+/// Datagrams received or sent with one syscall.
+pub const BATCH_SIZE: usize = 32;
+
+/// Largest udp payload, which is also the most the kernel coalesces with GRO
+/// or splits up with GSO.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Most segments the kernel accepts in one GSO send.
+#[cfg(target_os = "linux")]
+const MAX_GSO_SEGMENTS: usize = 64;
+
+/// Turned off for good when the first GSO send fails, e.g. because the
+/// network device doesn't support the checksum offload it needs.
+#[cfg(target_os = "linux")]
+static GSO_SUPPORTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Turns on GRO for the socket and checks whether the kernel supports GSO,
+/// returns whether GRO is on.
+#[cfg(target_os = "linux")]
+pub fn enable_offload(socket: &socket2::Socket) -> bool {
+    use std::{os::fd::AsRawFd, sync::atomic::Ordering};
+
+    let fd = socket.as_raw_fd();
+    let on: libc::c_int = 1;
+    let gro = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    } == 0;
+
+    let mut segment_size: libc::c_int = 0;
+    let mut size = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let gso = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &mut segment_size as *mut libc::c_int as *mut libc::c_void,
+            &mut size,
+        )
+    } == 0;
+    if gso {
+        GSO_SUPPORTED.store(true, Ordering::Relaxed);
+    }
+
+    gro
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_offload(_socket: &socket2::Socket) -> bool {
+    false
+}
+
+/// Buffers for receiving a batch of datagrams with one syscall.
+pub struct RecvBatch {
+    buffers: Vec<Vec<u8>>,
+    received: Vec<Received>,
+}
+
+struct Received {
+    /// the buffer it was received into
+    buffer: usize,
+    size: usize,
+    remote: SocketAddr,
+    /// size of the datagrams the kernel coalesced into this one with GRO
+    segment_size: usize,
+}
+
+impl RecvBatch {
+    /// With GRO the buffers have to be `MAX_DATAGRAM_SIZE` large.
+    pub fn new(buffer_size: usize) -> Self {
+        RecvBatch {
+            buffers: vec![vec![0; buffer_size]; BATCH_SIZE],
+            received: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Returns the datagrams of the last receive, coalesced ones split up again.
+    pub fn datagrams(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received.iter().flat_map(|received| {
+            self.buffers[received.buffer][..received.size]
+                .chunks(received.segment_size.max(1))
+                .map(|datagram| (datagram, received.remote))
+        })
+    }
+
+    /// Receives whatever is queued on the socket without waiting, fails with
+    /// `WouldBlock` if nothing is.
+    #[cfg(target_os = "linux")]
+    pub fn try_recv(&mut self, udp: &UdpSocket) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        self.received.clear();
+
+        let mut names: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        let mut controls = [Control([0; CONTROL_SIZE]); BATCH_SIZE];
+        let mut iovecs: Vec<libc::iovec> = self
+            .buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            })
+            .collect();
+
+        let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        for (i, header) in headers.iter_mut().enumerate() {
+            header.msg_hdr.msg_name = &mut names[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_hdr.msg_iov = &mut iovecs[i];
+            header.msg_hdr.msg_iovlen = 1;
+            header.msg_hdr.msg_control = controls[i].0.as_mut_ptr() as *mut libc::c_void;
+            header.msg_hdr.msg_controllen = CONTROL_SIZE as _;
+        }
+
+        let count = unsafe {
+            libc::recvmmsg(
+                udp.as_raw_fd(),
+                headers.as_mut_ptr(),
+                BATCH_SIZE as libc::c_uint,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (buffer, (header, name)) in headers.iter().zip(names).take(count as usize).enumerate() {
+            let address = unsafe { socket2::SockAddr::new(name, header.msg_hdr.msg_namelen) };
+            let Some(remote) = address.as_socket() else {
+                continue;
+            };
+            // the rest of a datagram larger than the buffer is lost
+            if header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                continue;
+            }
+
+            let size = header.msg_len as usize;
+            self.received.push(Received {
+                buffer,
+                size,
+                remote,
+                segment_size: gro_segment_size(&header.msg_hdr).unwrap_or(size),
+            });
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn try_recv(&mut self, udp: &UdpSocket) -> io::Result<()> {
+        self.received.clear();
+
+        let (size, remote) = udp.try_recv_from(&mut self.buffers[0])?;
+        self.received.push(Received {
+            buffer: 0,
+            size,
+            remote,
+            segment_size: size,
+        });
+
+        Ok(())
+    }
+}
+
+/// Space for the one control message used, a GRO segment size or a GSO one.
+#[cfg(target_os = "linux")]
+const CONTROL_SIZE: usize = 32;
+
+/// Control message buffer with the alignment `cmsghdr` needs.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy)]
+#[repr(align(8))]
+struct Control([u8; CONTROL_SIZE]);
+
+#[cfg(target_os = "linux")]
+fn gro_segment_size(header: &libc::msghdr) -> Option<usize> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(header) };
+    while !cmsg.is_null() {
+        let message = unsafe { &*cmsg };
+        if message.cmsg_level == libc::SOL_UDP && message.cmsg_type == libc::UDP_GRO {
+            let size = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) };
+            return Some(size as usize);
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(header, cmsg) };
+    }
+
+    None
+}
+
+/// Collects the datagrams to one destination and sends them with as few
+/// syscalls as possible.
+///
+/// The datagrams are written directly into one buffer, one after the other,
+/// which is how GSO expects them.
+#[derive(Default)]
+pub struct SendBatch {
+    buffer: Vec<u8>,
+    /// sizes of the datagrams in `buffer`
+    datagrams: Vec<usize>,
+    /// end of the last datagram in `buffer`
+    filled: usize,
+}
+
+/// Datagrams sent together, either one or several with the same size
+/// except for the last one, which may be shorter.
+#[cfg(target_os = "linux")]
+struct Group {
+    start: usize,
+    size: usize,
+    segment_size: usize,
+}
+
+impl SendBatch {
+    /// Returns a buffer of `size` bytes for the next datagram, which is only
+    /// added by `commit`.
+    pub fn reserve(&mut self, size: usize) -> &mut [u8] {
+        self.buffer.resize(self.filled + size, 0);
+        &mut self.buffer[self.filled..]
+    }
+
+    /// Adds the first `size` bytes of the reserved buffer as a datagram.
+    pub fn commit(&mut self, size: usize) {
+        self.filled += size;
+        self.datagrams.push(size);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.datagrams.len() >= BATCH_SIZE
+    }
+
+    /// Sends all datagrams, the batch is empty afterwards even if that failed.
+    pub async fn flush(&mut self, udp: &UdpSocket, remote: SocketAddr) -> io::Result<()> {
+        let result = self.send(udp, remote).await;
+
+        self.buffer.clear();
+        self.datagrams.clear();
+        self.filled = 0;
+
+        result
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn send(&self, udp: &UdpSocket, remote: SocketAddr) -> io::Result<()> {
+        use std::sync::atomic::Ordering;
+        use tokio::io::Interest;
+
+        let mut gso = GSO_SUPPORTED.load(Ordering::Relaxed);
+        let mut groups = self.groups(gso);
+        let mut sent = 0;
+
+        while sent < groups.len() {
+            match udp
+                .async_io(Interest::WRITABLE, || self.try_send(udp, remote, &groups[sent..]))
+                .await
+            {
+                Ok(count) => sent += count,
+                Err(e) if gso && e.raw_os_error() == Some(libc::EIO) => {
+                    println!("udp segmentation offload failed, sending datagrams one by one");
+                    GSO_SUPPORTED.store(false, Ordering::Relaxed);
+
+                    // the failed group and everything after it is sent again without GSO
+                    let start = groups[sent].start;
+                    gso = false;
+                    groups = self.groups(false);
+                    sent = groups.iter().position(|group| group.start == start).unwrap_or(0);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn send(&self, udp: &UdpSocket, remote: SocketAddr) -> io::Result<()> {
+        let mut start = 0;
+        for size in &self.datagrams {
+            udp.send_to(&self.buffer[start..start + size], remote).await?;
+            start += size;
+        }
+
+        Ok(())
+    }
+
+    /// Splits the datagrams into what can be sent with one GSO message each.
+    #[cfg(target_os = "linux")]
+    fn groups(&self, gso: bool) -> Vec<Group> {
+        let mut groups: Vec<Group> = Vec::new();
+        let mut start = 0;
+
+        for &size in &self.datagrams {
+            let fits = groups.last().is_some_and(|group| {
+                gso && size <= group.segment_size
+                    // only the last segment may be shorter
+                    && group.size % group.segment_size == 0
+                    && group.size / group.segment_size < MAX_GSO_SEGMENTS
+                    && group.size + size <= MAX_DATAGRAM_SIZE
+            });
+
+            match groups.last_mut() {
+                Some(group) if fits => group.size += size,
+                _ => groups.push(Group {
+                    start,
+                    size,
+                    segment_size: size,
+                }),
+            }
+            start += size;
+        }
+
+        groups
+    }
+
+    /// Sends the groups with one syscall, returns how many were sent.
+    #[cfg(target_os = "linux")]
+    fn try_send(&self, udp: &UdpSocket, remote: SocketAddr, groups: &[Group]) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        let groups = &groups[..groups.len().min(BATCH_SIZE)];
+        let address = socket2::SockAddr::from(remote);
+
+        let mut controls = [Control([0; CONTROL_SIZE]); BATCH_SIZE];
+        let mut iovecs: Vec<libc::iovec> = groups
+            .iter()
+            .map(|group| libc::iovec {
+                iov_base: self.buffer[group.start..].as_ptr() as *mut libc::c_void,
+                iov_len: group.size,
+            })
+            .collect();
+
+        let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        for (i, group) in groups.iter().enumerate() {
+            let header = &mut headers[i].msg_hdr;
+            header.msg_name = address.as_ptr() as *mut libc::c_void;
+            header.msg_namelen = address.len();
+            header.msg_iov = &mut iovecs[i];
+            header.msg_iovlen = 1;
+
+            if group.size > group.segment_size {
+                header.msg_control = controls[i].0.as_mut_ptr() as *mut libc::c_void;
+                header.msg_controllen = CONTROL_SIZE as _;
+
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(header);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<u16>() as u32) as _;
+                    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, group.segment_size as u16);
+                    header.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<u16>() as u32) as _;
+                }
+            }
+        }
+
+        let count = unsafe {
+            libc::sendmmsg(
+                udp.as_raw_fd(),
+                headers.as_mut_ptr(),
+                groups.len() as libc::c_uint,
+                libc::MSG_DONTWAIT as _,
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(count as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests;
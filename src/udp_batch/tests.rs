@@ -0,0 +1,147 @@
+use std::net::SocketAddr;
+
+use super::*;
+
+fn remote(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// A batch which received `received` into buffers filled with their index.
+fn recv_batch(received: Vec<Received>) -> RecvBatch {
+    RecvBatch {
+        buffers: (0..BATCH_SIZE).map(|i| vec![i as u8; 4096]).collect(),
+        received,
+    }
+}
+
+#[test]
+fn datagrams_are_taken_from_their_buffer() {
+    // the datagram received into the second buffer was the only valid one
+    let batch = recv_batch(vec![Received {
+        buffer: 1,
+        size: 100,
+        remote: remote(1),
+        segment_size: 100,
+    }]);
+
+    let datagrams: Vec<_> = batch.datagrams().collect();
+    assert_eq!(datagrams, [(&[1; 100][..], remote(1))]);
+}
+
+#[test]
+fn coalesced_datagrams_are_split() {
+    let batch = recv_batch(vec![
+        Received {
+            buffer: 0,
+            size: 3000,
+            remote: remote(1),
+            segment_size: 1200,
+        },
+        Received {
+            buffer: 1,
+            size: 500,
+            remote: remote(2),
+            segment_size: 500,
+        },
+    ]);
+
+    let datagrams: Vec<_> = batch
+        .datagrams()
+        .map(|(datagram, remote)| (datagram.len(), datagram[0], remote))
+        .collect();
+    assert_eq!(
+        datagrams,
+        [
+            (1200, 0, remote(1)),
+            (1200, 0, remote(1)),
+            (600, 0, remote(1)),
+            (500, 1, remote(2)),
+        ]
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn truncated_datagrams_are_skipped() {
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let local = udp.local_addr().unwrap();
+    sender.send_to(&[1; 200], local).await.unwrap();
+    sender.send_to(&[2; 100], local).await.unwrap();
+
+    // once the second datagram is there the first one was received as well
+    let mut batch = RecvBatch::new(100);
+    let mut datagrams: Vec<Vec<u8>> = Vec::new();
+    while !datagrams.iter().any(|datagram| datagram[0] == 2) {
+        udp.readable().await.unwrap();
+        match batch.try_recv(&udp) {
+            Ok(()) => datagrams.extend(batch.datagrams().map(|(datagram, _)| datagram.to_vec())),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    assert_eq!(datagrams, [vec![2; 100]]);
+}
+
+#[cfg(target_os = "linux")]
+fn send_batch(sizes: impl IntoIterator<Item = usize>) -> SendBatch {
+    let mut batch = SendBatch::default();
+    for size in sizes {
+        batch.reserve(size);
+        batch.commit(size);
+    }
+    batch
+}
+
+/// start, size and segment size of every group
+#[cfg(target_os = "linux")]
+fn groups(batch: &SendBatch, gso: bool) -> Vec<(usize, usize, usize)> {
+    batch
+        .groups(gso)
+        .iter()
+        .map(|group| (group.start, group.size, group.segment_size))
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn equal_datagrams_are_one_group() {
+    let batch = send_batch([100, 100, 100]);
+
+    assert_eq!(groups(&batch, true), [(0, 300, 100)]);
+    assert_eq!(groups(&batch, false), [(0, 100, 100), (100, 100, 100), (200, 100, 100)]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn only_the_last_segment_may_be_short() {
+    let batch = send_batch([100, 100, 50, 100, 120]);
+
+    // neither a datagram after a short one nor a larger one joins the group
+    assert_eq!(groups(&batch, true), [(0, 250, 100), (250, 100, 100), (350, 120, 120)]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn groups_are_limited_to_max_gso_segments() {
+    let batch = send_batch(std::iter::repeat_n(100, MAX_GSO_SEGMENTS + 1));
+
+    assert_eq!(
+        groups(&batch, true),
+        [(0, MAX_GSO_SEGMENTS * 100, 100), (MAX_GSO_SEGMENTS * 100, 100, 100)]
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn groups_are_limited_to_max_datagram_size() {
+    let per_group = MAX_DATAGRAM_SIZE / 1500;
+    assert!(per_group < MAX_GSO_SEGMENTS);
+    let batch = send_batch(std::iter::repeat_n(1500, per_group + 1));
+
+    assert_eq!(
+        groups(&batch, true),
+        [(0, per_group * 1500, 1500), (per_group * 1500, 1500, 1500)]
+    );
+}
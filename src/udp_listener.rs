@@ -0,0 +1,97 @@
+use std::{
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    task::Poll,
+};
+
+use anyhow::Context;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{io::Interest, net::UdpSocket};
+
+use crate::udp_batch::{enable_offload, RecvBatch};
+
+/// All udp sockets the server listens on.
+pub struct UdpListener {
+    sockets: Vec<Arc<UdpSocket>>,
+    /// socket which is polled first on the next receive, so that a busy
+    /// socket can't starve the others
+    next: usize,
+    /// whether the kernel may coalesce received datagrams on all sockets
+    gro: bool,
+}
+
+/// Returns the socket and whether GRO is turned on for it.
+fn bind_udp(address: SocketAddr, reuse_port: bool) -> io::Result<(UdpSocket, bool)> {
+    let socket = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+    if address.is_ipv6() {
+        // otherwise [::] and 0.0.0.0 can't be bound to the same port
+        socket.set_only_v6(true)?;
+    }
+    if reuse_port {
+        // the kernel spreads the peers over the sockets by their address
+        socket.set_reuse_port(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    let gro = enable_offload(&socket);
+
+    Ok((UdpSocket::from_std(socket.into())?, gro))
+}
+
+impl UdpListener {
+    /// With `reuse_port` further listeners can be bound to the same addresses.
+    pub fn bind(addresses: &[SocketAddr], reuse_port: bool) -> anyhow::Result<Self> {
+        let mut sockets = Vec::with_capacity(addresses.len());
+        let mut gro = true;
+        for address in addresses {
+            let (udp, udp_gro) = bind_udp(*address, reuse_port).with_context(|| format!("failed to bind {}", address))?;
+            sockets.push(Arc::new(udp));
+            gro &= udp_gro;
+        }
+
+        Ok(UdpListener { sockets, next: 0, gro })
+    }
+
+    /// With GRO the receive buffers have to hold `MAX_DATAGRAM_SIZE` bytes.
+    pub fn gro(&self) -> bool {
+        self.gro
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.sockets.iter().map(|udp| udp.local_addr()).collect()
+    }
+
+    /// Receives the next batch of datagrams from any of the sockets, returns
+    /// the socket they arrived on so that replies can be sent through the same one.
+    pub async fn recv_batch(&mut self, batch: &mut RecvBatch) -> io::Result<Arc<UdpSocket>> {
+        poll_fn(|cx| {
+            for i in 0..self.sockets.len() {
+                let index = (self.next + i) % self.sockets.len();
+                let udp = &self.sockets[index];
+
+                // a receive which would block clears the readiness, the next
+                // check registers for the next datagram
+                loop {
+                    match udp.poll_recv_ready(cx) {
+                        Poll::Ready(Ok(())) => {}
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => break,
+                    }
+
+                    match udp.try_io(Interest::READABLE, || batch.try_recv(udp)) {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                        result => {
+                            self.next = (index + 1) % self.sockets.len();
+                            return Poll::Ready(result.map(|()| udp.clone()));
+                        }
+                    }
+                }
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+}
@@ -1,89 +1,206 @@
-use std::collections::LinkedList;
+use std::{collections::VecDeque, mem, ops::Deref};
 
 use smoltcp::{phy::{RxToken, TxToken, Device, Checksum}, time::Instant};
 
+/// Packets queued in either direction before further ones are dropped, like
+/// on a congested link.
+const QUEUE_LIMIT: usize = 64;
+
+/// Reuses the buffers of processed packets, so that the packet path doesn't
+/// allocate once it is warmed up.
+struct BufferPool {
+    free: Vec<Vec<u8>>,
+    /// size of every buffer
+    size: usize,
+}
+
+impl BufferPool {
+    fn new(size: usize) -> Self {
+        BufferPool {
+            free: Vec::new(),
+            size,
+        }
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_else(|| vec![0; self.size])
+    }
+
+    fn recycle(&mut self, buffer: Vec<u8>) {
+        if self.free.len() < QUEUE_LIMIT {
+            self.free.push(buffer);
+        }
+    }
+}
+
+/// A packet in a pooled buffer, which is usually larger than the packet.
+pub struct Packet {
+    buffer: Vec<u8>,
+    size: usize,
+}
+
+impl Deref for Packet {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer[..self.size]
+    }
+}
+
 pub struct VirtualDevice {
-    packets_received: LinkedList<Vec<u8>>,
-    packets_to_send: LinkedList<Vec<u8>>,
+    mtu: usize,
+    packets_received: VecDeque<Packet>,
+    packets_to_send: VecDeque<Packet>,
+
+    receive_pool: BufferPool,
+    send_pool: BufferPool,
+    /// buffer the next packet from the tunnel is decapsulated into
+    spare: Vec<u8>,
 }
 
 impl VirtualDevice {
-    pub fn new() -> Self {
+    /// `receive_size` has to fit every packet decapsulated from the tunnel.
+    pub fn new(mtu: usize, receive_size: usize) -> Self {
+        let mut receive_pool = BufferPool::new(receive_size);
+        let spare = receive_pool.take();
+
         VirtualDevice {
-            packets_received: LinkedList::new(),
-            packets_to_send: LinkedList::new(),
+            mtu,
+            packets_received: VecDeque::with_capacity(QUEUE_LIMIT),
+            packets_to_send: VecDeque::with_capacity(QUEUE_LIMIT),
+
+            receive_pool,
+            send_pool: BufferPool::new(mtu),
+            spare,
         }
     }
 
+    /// The buffer the next packet from the tunnel is written into, it is
+    /// only queued by `commit_received`.
+    pub fn receive_buffer(&mut self) -> &mut [u8] {
+        &mut self.spare
+    }
+
+    /// The first `size` bytes of the receive buffer.
+    pub fn received(&self, size: usize) -> &[u8] {
+        &self.spare[..size]
+    }
+
+    /// Queues the first `size` bytes of the receive buffer as a packet.
+    pub fn commit_received(&mut self, size: usize) {
+        if self.packets_received.len() >= QUEUE_LIMIT {
+            return;
+        }
+
+        let buffer = mem::replace(&mut self.spare, self.receive_pool.take());
+        self.packets_received.push_back(Packet { buffer, size });
+    }
+
     pub fn add_received(&mut self, packet: &[u8]) {
-        // TODO: don't copy
-        self.packets_received.push_back(packet.into());
+        let Some(buffer) = self.spare.get_mut(..packet.len()) else {
+            return;
+        };
+        buffer.copy_from_slice(packet);
+        self.commit_received(packet.len());
     }
 
-    pub fn get_for_sending(&mut self) -> Option<Vec<u8>> {
+    /// Packets have to be handed back with `recycle` once they are sent.
+    pub fn get_for_sending(&mut self) -> Option<Packet> {
         self.packets_to_send.pop_front()
     }
-}
 
-pub struct PreReceivedRxToken {
-    buffer: Vec<u8>,
+    pub fn recycle(&mut self, packet: Packet) {
+        self.send_pool.recycle(packet.buffer);
+    }
 }
 
-impl PreReceivedRxToken {
-    pub fn new(buffer: Vec<u8>) -> Self {
-        PreReceivedRxToken { buffer }
-    }
+pub struct PreReceivedRxToken<'a> {
+    packet: Packet,
+    /// where the buffer goes after the packet was consumed
+    pool: &'a mut BufferPool,
 }
 
-impl RxToken for PreReceivedRxToken {
+impl RxToken for PreReceivedRxToken<'_> {
     fn consume<R, F>(mut self, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        f(&mut self.buffer)
+        let result = f(&mut self.packet.buffer[..self.packet.size]);
+        self.pool.recycle(self.packet.buffer);
+        result
     }
 }
 
-impl<'a> TxToken for &'a mut VirtualDevice {
+pub struct VirtualTxToken<'a> {
+    packets_to_send: &'a mut VecDeque<Packet>,
+    pool: &'a mut BufferPool,
+}
+
+impl TxToken for VirtualTxToken<'_> {
     fn consume<R, F>(self, len: usize, f: F) -> R
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut buf: Vec<u8> = vec![0; len];
-        let result = f(&mut buf[..]);
-        self.packets_to_send.push_back(buf);
-        return result;
+        let mut buffer = self.pool.take();
+        if buffer.len() < len {
+            buffer.resize(len, 0);
+        }
+        let result = f(&mut buffer[..len]);
+
+        // only a reply to a received packet can find the queue full
+        if self.packets_to_send.len() < QUEUE_LIMIT {
+            self.packets_to_send.push_back(Packet { buffer, size: len });
+        } else {
+            self.pool.recycle(buffer);
+        }
+        result
     }
 }
 
 impl Device for VirtualDevice {
-    type RxToken<'a> = PreReceivedRxToken
+    type RxToken<'a> = PreReceivedRxToken<'a>
     where
         Self: 'a;
 
-    type TxToken<'a> = &'a mut VirtualDevice
+    type TxToken<'a> = VirtualTxToken<'a>
     where
         Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        let Some(packet) = self.packets_received.pop_front() else { return None };
-        return Some((PreReceivedRxToken::new(packet), self));
+        let packet = self.packets_received.pop_front()?;
+        Some((
+            PreReceivedRxToken {
+                packet,
+                pool: &mut self.receive_pool,
+            },
+            VirtualTxToken {
+                packets_to_send: &mut self.packets_to_send,
+                pool: &mut self.send_pool,
+            },
+        ))
     }
 
+    /// Nothing is sent while the queue is full, smoltcp tries again on the next poll.
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        return Some(self);
+        if self.packets_to_send.len() >= QUEUE_LIMIT {
+            return None;
+        }
+
+        Some(VirtualTxToken {
+            packets_to_send: &mut self.packets_to_send,
+            pool: &mut self.send_pool,
+        })
     }
 
     fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
-        let mtu = 1400;
         let mut caps = smoltcp::phy::DeviceCapabilities::default();
         caps.medium = smoltcp::phy::Medium::Ip;
-        caps.max_transmission_unit = mtu;
+        caps.max_transmission_unit = self.mtu;
         caps.checksum = smoltcp::phy::ChecksumCapabilities::ignored();
         caps.checksum.tcp = Checksum::Tx;
         caps.checksum.ipv4 = Checksum::Tx;
         caps.checksum.icmpv4 = Checksum::Tx;
         caps.checksum.icmpv6 = Checksum::Tx;
-        return caps;
+        caps
     }
-}
\ No newline at end of file
+}
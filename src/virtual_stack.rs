@@ -0,0 +1,148 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use smoltcp::{
+    iface::{Config, Interface},
+    time::Instant,
+    wire::{HardwareAddress, IpAddress, IpCidr},
+};
+
+use crate::{
+    config::{PeerConfig, ServiceConfig},
+    tcp_forwarder::TcpForwarder,
+    virtual_device::{Packet, VirtualDevice},
+    wakeup::Wakeup,
+};
+
+/// The ip stack of a session, fed with the packets decapsulated from the tunnel.
+pub struct VirtualStack<'a> {
+    interface: Interface,
+    device: VirtualDevice,
+
+    tcp_forwarder: TcpForwarder<'a>,
+
+    peer_name: String,
+    allowed_ips: Vec<IpCidr>,
+    /// packets dropped because their source is outside of `allowed_ips`,
+    /// since they were logged last
+    dropped_packets: u64,
+    last_drop_log: Option<std::time::Instant>,
+}
+
+/// Dropped packets are logged at most that often, so that a peer spoofing
+/// sources can't flood the log.
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Checks the source of a decapsulated packet against the peer's AllowedIPs,
+/// the same way the kernel implementation does.
+fn is_allowed_source(allowed_ips: &[IpCidr], source: IpAddr) -> bool {
+    let source = IpAddress::from(source);
+    allowed_ips.iter().any(|cidr| cidr.contains_addr(&source))
+}
+
+impl<'a> VirtualStack<'a> {
+    pub fn new(
+        peer: &PeerConfig,
+        internal_addresses: &[IpAddr],
+        services: Arc<[ServiceConfig]>,
+        receive_size: usize,
+        wakeup: Wakeup,
+    ) -> Self {
+        let mut device = VirtualDevice::new(peer.tuning.mtu, receive_size);
+        let mut interface = Interface::new(
+            Config::new(HardwareAddress::Ip),
+            &mut device,
+            Instant::now(),
+        );
+
+        interface.update_ip_addrs(|addresses| {
+            for internal_address in internal_addresses {
+                let _ = addresses.push(IpCidr::new((*internal_address).into(), 0));
+            }
+        });
+
+        VirtualStack {
+            interface,
+            device,
+
+            tcp_forwarder: TcpForwarder::new(
+                internal_addresses,
+                services,
+                peer.tuning.tcp_buffer_size,
+                wakeup,
+            ),
+
+            peer_name: peer.name.clone(),
+            allowed_ips: peer.allowed_ips.clone(),
+            dropped_packets: 0,
+            last_drop_log: None,
+        }
+    }
+
+    /// Buffer the next packet from the tunnel is decapsulated into.
+    pub fn receive_buffer(&mut self) -> &mut [u8] {
+        self.device.receive_buffer()
+    }
+
+    /// Hands the first `size` bytes of the receive buffer to the stack,
+    /// `source` is the inner source address reported by boringtun.
+    pub fn receive(&mut self, size: usize, source: IpAddr) {
+        if !is_allowed_source(&self.allowed_ips, source) {
+            self.dropped_packets += 1;
+            if self.last_drop_log.is_some_and(|at| at.elapsed() < DROP_LOG_INTERVAL) {
+                return;
+            }
+
+            println!(
+                "dropped {} packets outside of allowed ips of peer {}, the last one from {}",
+                self.dropped_packets, self.peer_name, source
+            );
+            self.dropped_packets = 0;
+            self.last_drop_log = Some(std::time::Instant::now());
+            return;
+        }
+
+        match self.tcp_forwarder.prepare_listener(self.device.received(size)) {
+            Ok(true) => {}
+            // a SYN waiting for its upstream
+            Ok(false) => return,
+            Err(e) => println!("failed to listen for peer {}: {:?}", self.peer_name, e),
+        }
+        self.device.commit_received(size);
+        self.poll();
+    }
+
+    /// Packets have to be handed back with `recycle` once they are sent.
+    pub fn get_for_sending(&mut self) -> Option<Packet> {
+        self.device.get_for_sending()
+    }
+
+    pub fn recycle(&mut self, packet: Packet) {
+        self.device.recycle(packet);
+    }
+
+    pub fn poll(&mut self) {
+        for syn in self.tcp_forwarder.resolve_pending() {
+            self.device.add_received(&syn);
+        }
+
+        self.interface.poll(
+            Instant::now(),
+            &mut self.device,
+            self.tcp_forwarder.sockets(),
+        );
+
+        self.tcp_forwarder.update();
+    }
+
+    pub fn handle_tcp(&mut self) {
+        self.tcp_forwarder.process();
+        self.tcp_forwarder.update();
+    }
+
+    /// Time until the stack wants to be polled again, if it waits for a timer.
+    pub fn poll_delay(&mut self) -> Option<Duration> {
+        self.interface
+            .poll_delay(Instant::now(), self.tcp_forwarder.sockets())
+            .map(Duration::from)
+    }
+}
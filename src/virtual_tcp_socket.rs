@@ -1,151 +1,319 @@
-use std::io::{self, Error};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
 
-use smoltcp::socket::tcp::{SendError, Socket};
+use smoltcp::socket::tcp::Socket;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{
+        mpsc::{
+            error::{SendError, TryRecvError, TrySendError},
+            OwnedPermit,
+        },
+        Notify,
+    },
+};
+
+use crate::{
+    error::ConnectionError,
+    shared_pool::{PooledBuffer, SharedPool},
+    wakeup::Wakeup,
+};
+
+/// Largest chunk of data passed through the channels in one message.
+pub const MAX_CHUNK_SIZE: usize = 4096;
+
+type ReserveFuture =
+    Pin<Box<dyn Future<Output = Result<OwnedPermit<PooledBuffer>, SendError<()>>> + Send>>;
 
 pub struct VirtualTcpSocket {}
 
+/// Set by either side when the connection was reset instead of closed.
+#[derive(Default)]
+struct ResetState {
+    reset: AtomicBool,
+    /// wakes the upstream when the sync side resets the connection
+    notify: Notify,
+}
+
+/// Lets the upstream wait for a reset by the sync side while the async side
+/// is busy forwarding.
+pub struct ResetSignal {
+    state: Arc<ResetState>,
+}
+
+impl ResetSignal {
+    /// Completes once the sync side reset the connection or is gone before
+    /// both directions were done.
+    pub async fn reset(&self) {
+        while !self.state.reset.load(Ordering::SeqCst) {
+            self.state.notify.notified().await;
+        }
+    }
+}
+
 pub struct VirtualTcpSocketSyncSide {
-    reciever: tokio::sync::mpsc::Receiver<Vec<u8>>,
-    sender: tokio::sync::mpsc::Sender<Vec<u8>>,
+    reciever: tokio::sync::mpsc::Receiver<PooledBuffer>,
+    /// gone once the peer closed its side, which ends the reading of the async side
+    sender: Option<tokio::sync::mpsc::Sender<PooledBuffer>>,
+
+    /// chunk from the upstream which didn't fit into the send buffer yet,
+    /// together with how much of it was already sent
+    pending: Option<(PooledBuffer, usize)>,
+    /// set once the upstream shut down its side
+    upstream_done: bool,
 
-    close_virtual_receiver: tokio::sync::oneshot::Receiver<()>,
+    reset: Arc<ResetState>,
+    /// the chunks go back to it once the async side is done with them
+    chunk_pool: SharedPool,
 }
 
+/// The virtual socket as seen from tokio, reads and writes go through the
+/// channels to the sync side.
 pub struct VirtualTcpSocketAsyncSide {
-    reciever: tokio::sync::mpsc::Receiver<Vec<u8>>,
-    sender: tokio::sync::mpsc::Sender<Vec<u8>>,
+    reciever: tokio::sync::mpsc::Receiver<PooledBuffer>,
+    /// gone after a shutdown, which makes the sync side close the socket
+    sender: Option<tokio::sync::mpsc::Sender<PooledBuffer>>,
 
-    close_virtual_sender: Option<tokio::sync::oneshot::Sender<()>>,
+    /// chunk which was only partially read, together with how much of it was read
+    read_pending: Option<(PooledBuffer, usize)>,
+    /// waits for room in the channel while it is full
+    reserve: Option<ReserveFuture>,
+
+    reset: Arc<ResetState>,
+    chunk_pool: SharedPool,
+    /// lets the session move the data, or notice the shutdown
+    wakeup: Wakeup,
 }
 
 impl VirtualTcpSocket {
-    pub fn new() -> (VirtualTcpSocketSyncSide, VirtualTcpSocketAsyncSide) {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        wakeup: Wakeup,
+        chunk_pool: SharedPool,
+    ) -> (VirtualTcpSocketSyncSide, VirtualTcpSocketAsyncSide) {
         let (a_sender, a_receiver) = tokio::sync::mpsc::channel(8);
         let (b_sender, b_receiver) = tokio::sync::mpsc::channel(8);
-
-        let (close_virtual_sender, close_virtual_receiver) = tokio::sync::oneshot::channel();
+        let reset = Arc::new(ResetState::default());
 
         (
             VirtualTcpSocketSyncSide {
                 reciever: b_receiver,
-                sender: a_sender,
-                close_virtual_receiver,
+                sender: Some(a_sender),
+                pending: None,
+                upstream_done: false,
+                reset: reset.clone(),
+                chunk_pool: chunk_pool.clone(),
             },
             VirtualTcpSocketAsyncSide {
                 reciever: a_receiver,
-                sender: b_sender,
-                close_virtual_sender: Some(close_virtual_sender)
+                sender: Some(b_sender),
+                read_pending: None,
+                reserve: None,
+                reset,
+                chunk_pool,
+                wakeup,
             },
         )
     }
 }
 
 impl VirtualTcpSocketAsyncSide {
-    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.reciever.recv().await {
-            Some(received) => {
-                if buf.len() < received.len() {
-                    return Err(Error::new(io::ErrorKind::Other, "buffer is too small"));
-                }
-
-                buf[0..received.len()].clone_from_slice(&received);
+    /// Makes the sync side answer the peer with a RST instead of a FIN.
+    pub fn reset(&self) {
+        self.reset.reset.store(true, Ordering::SeqCst);
+        self.wakeup.wake();
+    }
 
-                return Ok(received.len());
-            }
-            None => {
-                return Err(Error::new(
-                    io::ErrorKind::BrokenPipe,
-                    "the connection was closed",
-                ))
-            }
+    pub fn reset_signal(&self) -> ResetSignal {
+        ResetSignal {
+            state: self.reset.clone(),
         }
     }
+}
+
+impl Drop for VirtualTcpSocketAsyncSide {
+    fn drop(&mut self) {
+        self.wakeup.wake();
+    }
+}
+
+fn connection_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "the virtual connection is gone")
+}
+
+impl AsyncRead for VirtualTcpSocketAsyncSide {
+    /// Reaches the end once the peer closed its side, fails when it reset the
+    /// connection.
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let (chunk, read) = match self.read_pending.take() {
+            Some(pending) => pending,
+            None => match self.reciever.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    // there is room in the channel again
+                    self.wakeup.wake();
+                    (chunk, 0)
+                }
+                Poll::Ready(None) if self.reset.reset.load(Ordering::SeqCst) => {
+                    return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            },
+        };
 
-    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if let Err(e) = self.sender.send(buf.to_vec()).await {
-            return Err(Error::new(io::ErrorKind::Other, e));
+        let size = buf.remaining().min(chunk.len() - read);
+        buf.put_slice(&chunk[read..read + size]);
+
+        if read + size < chunk.len() {
+            self.read_pending = Some((chunk, read + size));
         }
 
-        Ok(buf.len())
+        Poll::Ready(Ok(()))
     }
+}
 
-    pub async fn write_all(&mut self, buf: &mut [u8]) -> io::Result<()> {
-        let mut done = 0;
-        loop {
-            if done >= buf.len() {
-                return Ok(());
+impl AsyncWrite for VirtualTcpSocketAsyncSide {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let permit = match self.reserve.as_mut() {
+            Some(reserve) => {
+                let result = std::task::ready!(reserve.as_mut().poll(cx));
+                self.reserve = None;
+                result.map_err(|_| connection_gone())?
             }
-            match self.write(&mut buf[done..]).await {
-                Ok(size) => {
-                    done += size;
+            None => {
+                let Some(sender) = &self.sender else {
+                    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+                };
+                match sender.clone().try_reserve_owned() {
+                    Ok(permit) => permit,
+                    Err(TrySendError::Full(sender)) => {
+                        self.reserve = Some(Box::pin(sender.reserve_owned()));
+                        return self.poll_write(cx, buf);
+                    }
+                    Err(TrySendError::Closed(_)) => return Poll::Ready(Err(connection_gone())),
                 }
-                Err(e) => return Err(e),
             }
-        }
+        };
+
+        let size = buf.len().min(MAX_CHUNK_SIZE);
+        let mut chunk = self.chunk_pool.take();
+        chunk.extend_from_slice(&buf[..size]);
+        permit.send(chunk);
+        self.wakeup.wake();
+
+        Poll::Ready(Ok(size))
     }
 
-    pub async fn close(&mut self) {
-        if let Some(close_virtual_sender) = self.close_virtual_sender.take() {
-            let _ = close_virtual_sender.send(());
-        }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// The sync side closes the socket after everything written before was sent.
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.reserve = None;
+        self.sender = None;
+        self.wakeup.wake();
+
+        Poll::Ready(Ok(()))
     }
 }
 
 impl VirtualTcpSocketSyncSide {
-    pub fn process(&mut self, socket: &mut Socket<'_>) {
-        if let Ok(_) = self.close_virtual_receiver.try_recv() {
-            socket.close();
+    /// Makes the upstream get reset too, even if it already read the EOF.
+    pub fn reset(&self) {
+        self.reset.reset.store(true, Ordering::SeqCst);
+        self.reset.notify.notify_one();
+    }
+
+    /// Both sides closed their direction, the socket closing is no reset.
+    pub fn is_finished(&self) -> bool {
+        self.sender.is_none() && self.upstream_done
+    }
+
+    /// Moves data between the socket and the channels, a FIN from either
+    /// side only closes the one direction.
+    pub fn process(&mut self, socket: &mut Socket<'_>) -> Result<(), ConnectionError> {
+        if self.reset.reset.load(Ordering::SeqCst) {
+            socket.abort();
+            return Ok(());
         }
 
-        if socket.can_recv() {
-            // let receive_amount = socket.recv_capacity() - socket.recv_queue();
-
-            let mut buf: Vec<u8> = vec![0; 4096];
-
-            match socket.recv_slice(&mut buf[..]) {
-                Ok(received) => {
-                    // TODO: can be optimized
-                    match self.sender.try_send(buf[..received].into()) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            match e {
-                                tokio::sync::mpsc::error::TrySendError::Full(_) => {
-                                    // maybe we should use peek_slice and check weather we have enough
-                                    todo!()
-                                }
-                                tokio::sync::mpsc::error::TrySendError::Closed(_) => {
-                                    socket.close();
-                                }
-                            }
-                        }
-                    }
+        // only take what the upstream accepts, the rest stays in the receive
+        // buffer and shrinks the window advertised to the peer
+        while let Some(sender) = self.sender.as_ref().filter(|_| socket.can_recv()) {
+            let permit = match sender.try_reserve() {
+                Ok(permit) => permit,
+                Err(TrySendError::Full(_)) => break,
+                Err(TrySendError::Closed(_)) => {
+                    socket.close();
+                    break;
                 }
-                Err(_) => todo!(),
-            }
+            };
+
+            let mut chunk = self.chunk_pool.take();
+            socket.recv(|data| {
+                let size = data.len().min(MAX_CHUNK_SIZE);
+                chunk.extend_from_slice(&data[..size]);
+                (size, ())
+            })?;
+            permit.send(chunk);
         }
 
-        if socket.can_send() {
-            let send_space = socket.send_capacity() - socket.send_queue();
-
-            if send_space > 16 * 1024 {
-                // for now we only send stuff if we have enough space
-                // so that we have too less for edge cases
-
-                match self.reciever.try_recv() {
-                    Ok(buffer) => {
-                        match socket.send_slice(&buffer[..]) {
-                            Ok(sent) => {
-                                assert!(sent == buffer.len())
-                            }
-                            Err(e) if e == SendError::InvalidState => todo!(),
-                            Err(_) => todo!(),
-                        };
+        // the peer sent its FIN and everything before it was handed over
+        if !socket.may_recv() {
+            self.sender = None;
+        }
+
+        while socket.can_send() && socket.send_queue() < socket.send_capacity() {
+            let (chunk, sent) = match self.pending.take() {
+                Some(pending) => pending,
+                None => match self.reciever.try_recv() {
+                    Ok(chunk) => (chunk, 0),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        // the upstream shut down its side or is gone, nothing more will be sent
+                        self.upstream_done = true;
+                        socket.close();
+                        break;
                     }
-                    Err(e) if e == tokio::sync::mpsc::error::TryRecvError::Empty => {}
-                    Err(e) if e == tokio::sync::mpsc::error::TryRecvError::Disconnected => {}
-                    Err(_) => todo!(),
-                };
+                },
+            };
+
+            let sent = sent + socket.send_slice(&chunk[sent..])?;
+            if sent < chunk.len() {
+                self.pending = Some((chunk, sent));
             }
         }
+
+        Ok(())
     }
 }
+
+/// A socket which is gone before both sides were done was reset by the peer,
+/// timed out or belonged to a session which ended.
+impl Drop for VirtualTcpSocketSyncSide {
+    fn drop(&mut self) {
+        if !self.is_finished() {
+            self.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,259 @@
+use std::{collections::VecDeque, time::Duration};
+
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken},
+    socket::tcp,
+    time::Instant,
+    wire::{HardwareAddress, IpAddress, IpCidr},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+use super::*;
+
+/// Hands every transmitted packet back to the interface.
+#[derive(Default)]
+struct Loopback {
+    packets: VecDeque<Vec<u8>>,
+}
+
+struct LoopbackRxToken(Vec<u8>);
+
+struct LoopbackTxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl RxToken for LoopbackRxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl TxToken for LoopbackTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+impl Device for Loopback {
+    type RxToken<'a> = LoopbackRxToken
+    where
+        Self: 'a;
+
+    type TxToken<'a> = LoopbackTxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.packets.pop_front()?;
+        Some((LoopbackRxToken(packet), LoopbackTxToken(&mut self.packets)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(LoopbackTxToken(&mut self.packets))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = 1500;
+        caps
+    }
+}
+
+/// The socket of a virtual connection, connected to a peer over loopback.
+struct Connection {
+    interface: Interface,
+    device: Loopback,
+    sockets: SocketSet<'static>,
+    socket: SocketHandle,
+    peer: SocketHandle,
+}
+
+const BUFFER_SIZE: usize = 65535;
+
+fn tcp_socket() -> tcp::Socket<'static> {
+    let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; BUFFER_SIZE]),
+    );
+    socket.set_ack_delay(None);
+    socket
+}
+
+impl Connection {
+    fn new() -> Self {
+        let mut device = Loopback::default();
+        let mut interface = Interface::new(Config::new(HardwareAddress::Ip), &mut device, Instant::now());
+        interface.update_ip_addrs(|addresses| {
+            let _ = addresses.push(IpCidr::new(IpAddress::v4(10, 0, 0, 1), 24));
+        });
+
+        let mut socket = tcp_socket();
+        socket.listen(80).unwrap();
+        let mut peer = tcp_socket();
+        peer.connect(interface.context(), (IpAddress::v4(10, 0, 0, 1), 80), 50000)
+            .unwrap();
+
+        let mut sockets = SocketSet::new(vec![]);
+        let socket = sockets.add(socket);
+        let peer = sockets.add(peer);
+
+        let mut connection = Connection {
+            interface,
+            device,
+            sockets,
+            socket,
+            peer,
+        };
+        connection.poll();
+        assert_eq!(connection.socket().state(), tcp::State::Established);
+        connection
+    }
+
+    /// Runs the stack until no packets are left.
+    fn poll(&mut self) {
+        for _ in 0..100 {
+            self.interface.poll(Instant::now(), &mut self.device, &mut self.sockets);
+            if self.device.packets.is_empty() {
+                return;
+            }
+        }
+        panic!("the stack doesn't settle");
+    }
+
+    fn socket(&mut self) -> &mut tcp::Socket<'static> {
+        self.sockets.get_mut(self.socket)
+    }
+
+    fn peer(&mut self) -> &mut tcp::Socket<'static> {
+        self.sockets.get_mut(self.peer)
+    }
+
+    /// Sends `data` from the peer into the socket.
+    fn send(&mut self, data: &[u8]) {
+        assert_eq!(self.peer().send_slice(data).unwrap(), data.len());
+        self.poll();
+    }
+
+    fn process(&mut self, sync_side: &mut VirtualTcpSocketSyncSide) {
+        sync_side.process(self.sockets.get_mut(self.socket)).unwrap();
+        self.poll();
+    }
+
+    /// Everything the peer received so far.
+    fn received(&mut self) -> Vec<u8> {
+        let mut received = Vec::new();
+        while self.peer().can_recv() {
+            self.peer()
+                .recv(|data| {
+                    received.extend_from_slice(data);
+                    (data.len(), ())
+                })
+                .unwrap();
+        }
+        received
+    }
+}
+
+fn virtual_socket() -> (VirtualTcpSocketSyncSide, VirtualTcpSocketAsyncSide) {
+    VirtualTcpSocket::new(Wakeup::default(), SharedPool::new(8))
+}
+
+fn data(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn data_stays_in_the_socket_until_the_channel_has_room() {
+    let mut connection = Connection::new();
+    let (mut sync_side, mut async_side) = virtual_socket();
+
+    let data = data(12 * MAX_CHUNK_SIZE);
+    connection.send(&data);
+
+    // the channel holds 8 chunks, the rest isn't taken from the socket
+    connection.process(&mut sync_side);
+    assert_eq!(connection.socket().recv_queue(), 4 * MAX_CHUNK_SIZE);
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        received.extend_from_slice(&async_side.reciever.try_recv().unwrap());
+    }
+    connection.process(&mut sync_side);
+    assert_eq!(connection.socket().recv_queue(), 2 * MAX_CHUNK_SIZE);
+
+    while received.len() < data.len() {
+        match async_side.reciever.try_recv() {
+            Ok(chunk) => received.extend_from_slice(&chunk),
+            Err(_) => connection.process(&mut sync_side),
+        }
+    }
+    assert_eq!(received, data);
+}
+
+#[tokio::test]
+async fn reads_are_split_to_fit_the_buffer() {
+    let mut connection = Connection::new();
+    let (mut sync_side, mut async_side) = virtual_socket();
+
+    let data = data(MAX_CHUNK_SIZE + 100);
+    connection.send(&data);
+    connection.process(&mut sync_side);
+
+    let mut received = Vec::new();
+    while received.len() < data.len() {
+        let mut buf = [0; 1000];
+        let size = async_side.read(&mut buf).await.unwrap();
+        assert!(size > 0);
+        received.extend_from_slice(&buf[..size]);
+    }
+    assert_eq!(received, data);
+}
+
+#[tokio::test]
+async fn shutdown_closes_the_socket() {
+    let mut connection = Connection::new();
+    let (mut sync_side, mut async_side) = virtual_socket();
+
+    async_side.write_all(b"bye").await.unwrap();
+    async_side.shutdown().await.unwrap();
+    connection.process(&mut sync_side);
+
+    // everything written before the shutdown arrives before the FIN
+    assert_eq!(connection.received(), b"bye");
+    assert_eq!(connection.peer().state(), tcp::State::CloseWait);
+}
+
+#[tokio::test]
+async fn writes_wait_while_the_channel_is_full() {
+    let mut connection = Connection::new();
+    let (mut sync_side, mut async_side) = virtual_socket();
+
+    let data = data(9 * MAX_CHUNK_SIZE);
+    let mut chunks = data.chunks(MAX_CHUNK_SIZE);
+    for chunk in chunks.by_ref().take(8) {
+        assert_eq!(async_side.write(chunk).await.unwrap(), chunk.len());
+    }
+
+    let last = chunks.next().unwrap();
+    let blocked = timeout(Duration::from_millis(50), async_side.write(last)).await;
+    assert!(blocked.is_err(), "a write into the full channel didn't wait");
+
+    connection.process(&mut sync_side);
+    let written = timeout(Duration::from_secs(1), async_side.write(last)).await;
+    assert_eq!(written.unwrap().unwrap(), last.len());
+
+    connection.process(&mut sync_side);
+    assert_eq!(connection.received(), data);
+}
@@ -0,0 +1,21 @@
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+/// Wakes the task of a session whenever something outside of it produced
+/// work for it, e.g. an upstream wrote data into one of its virtual sockets.
+#[derive(Clone, Default)]
+pub struct Wakeup {
+    notify: Arc<Notify>,
+}
+
+impl Wakeup {
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    /// A wakeup while the session is busy is kept until it waits again.
+    pub async fn woken(&self) {
+        self.notify.notified().await;
+    }
+}
@@ -9,12 +9,26 @@ pub fn extract_handshake(private_key: &StaticSecret, buf: &[u8]) -> Option<HalfH
     let static_public = x25519::PublicKey::from(private_key);
     let Ok(handshake) = parse_handshake_anon(private_key, &static_public, &p) else {return None;};
 
-    return Some(handshake);
+    Some(handshake)
+}
+
+/// Returns the receiver index of messages addressed to an existing session,
+/// handshake initiations don't carry one.
+pub fn parse_receiver_index(buf: &[u8]) -> Option<u32> {
+    match Tunn::parse_incoming_packet(buf).ok()? {
+        Packet::HandshakeInit(_) => None,
+        Packet::HandshakeResponse(p) => Some(p.receiver_idx),
+        Packet::PacketCookieReply(p) => Some(p.receiver_idx),
+        Packet::PacketData(p) => Some(p.receiver_idx),
+    }
+}
+
+pub fn format_key(key: [u8; 32]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key)
 }
 
 pub fn print_key(key: [u8; 32]) {
-    let public_key = base64::engine::general_purpose::STANDARD.encode(key);
-    println!("handshake from {}", public_key);
+    println!("handshake from {}", format_key(key));
 }
 
 pub fn parse_key(x: &str) -> anyhow::Result<[u8; 32]> {
@@ -0,0 +1,304 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use boringtun::{noise::rate_limiter::RateLimiter, x25519::StaticSecret};
+use hashbrown::HashMap;
+use tokio::{net::UdpSocket, sync::mpsc};
+
+use crate::{
+    config::{PeerConfig, ServiceConfig},
+    handshake_guard::{HandshakeGuard, Verdict, COOKIE_REPLY_SIZE},
+    session::{Datagram, Session},
+    shared_pool::SharedPool,
+    udp_batch::{RecvBatch, MAX_DATAGRAM_SIZE},
+    udp_listener::UdpListener,
+    wireguard_helper::{extract_handshake, format_key, parse_receiver_index, print_key},
+};
+
+/// Datagrams queued for a session or a worker before further ones are dropped.
+const QUEUE_SIZE: usize = 256;
+
+/// What all workers share, everything else belongs to a single worker.
+pub struct Shared {
+    pub private_key: StaticSecret,
+    /// used by the handshake guards and the tunnels, so that a cookie handed out
+    /// by one worker is accepted by all of them
+    pub rate_limiter: Arc<RateLimiter>,
+    pub peers: HashMap<[u8; 32], PeerConfig>,
+    pub internal_addresses: Vec<IpAddr>,
+    pub services: Arc<[ServiceConfig]>,
+    pub session_timeout: Duration,
+    pub udp_buffer_size: usize,
+}
+
+/// How a datagram finds its session.
+enum Route {
+    /// the session index from the receiver index of the message
+    Index(u32),
+    /// the sender of a handshake initiation
+    Peer([u8; 32]),
+}
+
+/// A datagram another worker received for a session of this one.
+pub struct Handover {
+    route: Route,
+    datagram: Datagram,
+}
+
+/// Receives datagrams on its own sockets and runs the sessions of its peers.
+///
+/// The kernel picks the socket by the address of the peer, so after roaming a
+/// peer may show up at any worker. Handshake initiations are therefore handed
+/// to the peer's home worker, and every other message to the worker which
+/// owns the session index it is addressed to.
+pub struct Worker {
+    id: usize,
+    shared: Arc<Shared>,
+    udp_listener: UdpListener,
+    handshake_guard: HandshakeGuard,
+
+    /// inboxes of all workers by their id
+    inboxes: Arc<[mpsc::Sender<Handover>]>,
+    inbox: mpsc::Receiver<Handover>,
+    /// buffers of the datagrams handed to sessions, they come back once processed
+    datagram_pool: SharedPool,
+
+    // one session per peer, the peer addresses it with the upper 24 bits of its
+    // wireguard index in every message except the handshake initiation
+    connections: HashMap<[u8; 32], SessionHandle>,
+    indices: HashMap<u32, [u8; 32]>,
+    next_index: u32,
+}
+
+/// The dispatcher's end of a running session.
+struct SessionHandle {
+    index: u32,
+    datagrams: mpsc::Sender<Datagram>,
+}
+
+/// Creates the inboxes of `workers` workers.
+pub fn inboxes(workers: usize) -> (Arc<[mpsc::Sender<Handover>]>, Vec<mpsc::Receiver<Handover>>) {
+    let (senders, receivers): (Vec<_>, Vec<_>) =
+        (0..workers).map(|_| mpsc::channel(QUEUE_SIZE)).unzip();
+    (senders.into(), receivers)
+}
+
+impl Worker {
+    pub fn new(
+        id: usize,
+        shared: Arc<Shared>,
+        udp_listener: UdpListener,
+        handshake_guard: HandshakeGuard,
+        inboxes: Arc<[mpsc::Sender<Handover>]>,
+        inbox: mpsc::Receiver<Handover>,
+    ) -> Self {
+        Worker {
+            id,
+            shared,
+            udp_listener,
+            handshake_guard,
+
+            inboxes,
+            inbox,
+            datagram_pool: SharedPool::new(QUEUE_SIZE),
+
+            connections: HashMap::new(),
+            indices: HashMap::new(),
+            next_index: 0,
+        }
+    }
+
+    pub async fn run(mut self) {
+        // the sessions drive their stacks and wireguard timers themselves, they
+        // report here when they end so that their peer can start a new one
+        let (closed_sender, mut closed) = mpsc::unbounded_channel();
+
+        // with GRO the kernel hands over several datagrams at once
+        let mut recv_batch = RecvBatch::new(if self.udp_listener.gro() {
+            MAX_DATAGRAM_SIZE.max(self.shared.udp_buffer_size)
+        } else {
+            self.shared.udp_buffer_size
+        });
+        let mut cookie_buf = [0; COOKIE_REPLY_SIZE];
+
+        loop {
+            tokio::select! {
+                Some((peer_key, index)) = closed.recv() => {
+                    // the peer may already have a new session
+                    if self.connections.get(&peer_key).is_some_and(|session| session.index == index) {
+                        self.connections.remove(&peer_key);
+                    }
+                    self.indices.remove(&index);
+                }
+
+                Some(handover) = self.inbox.recv() => {
+                    self.dispatch(handover.route, handover.datagram, &closed_sender);
+                }
+
+                ret = self.udp_listener.recv_batch(&mut recv_batch) => {
+                    let udp = match ret {
+                        Ok(udp) => udp,
+                        Err(e) => {
+                            println!("failed to receive udp packet: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    for (buf, remote) in recv_batch.datagrams() {
+                        self.receive(buf, remote, &udp, &mut cookie_buf, &closed_sender).await;
+                    }
+                }
+            };
+        }
+    }
+
+    /// Passes a received datagram to the worker which owns its session.
+    async fn receive(
+        &mut self,
+        buf: &[u8],
+        remote: SocketAddr,
+        udp: &Arc<UdpSocket>,
+        cookie_buf: &mut [u8],
+        closed: &mpsc::UnboundedSender<([u8; 32], u32)>,
+    ) {
+        match self.handshake_guard.verify(buf, remote, cookie_buf) {
+            Verdict::Pass => {}
+            Verdict::CookieReply(cookie) => {
+                if let Err(e) = udp.send_to(cookie, remote).await {
+                    println!("failed to send cookie reply to {}: {:?}", remote, e);
+                }
+                return;
+            }
+            Verdict::Drop => return,
+        }
+
+        let route = match parse_receiver_index(buf) {
+            Some(receiver_index) => Route::Index(receiver_index >> 8),
+            None => match extract_handshake(&self.shared.private_key, buf) {
+                Some(handshake) => Route::Peer(handshake.peer_static_public),
+                None => return,
+            },
+        };
+        let owner = match &route {
+            Route::Index(index) => *index as usize % self.inboxes.len(),
+            Route::Peer(peer_key) => home_worker(peer_key, self.inboxes.len()),
+        };
+
+        let mut data = self.datagram_pool.take();
+        data.extend_from_slice(buf);
+        let datagram = Datagram { data, remote, udp: udp.clone() };
+        if owner == self.id {
+            self.dispatch(route, datagram, closed);
+        } else {
+            // the session replies through the socket it arrived on
+            let _ = self.inboxes[owner].try_send(Handover { route, datagram });
+        }
+    }
+
+    /// Passes a datagram to its session, a handshake initiation of a peer
+    /// without one starts it.
+    fn dispatch(
+        &mut self,
+        route: Route,
+        datagram: Datagram,
+        closed: &mpsc::UnboundedSender<([u8; 32], u32)>,
+    ) {
+        let peer_key = match route {
+            Route::Index(index) => self.indices.get(&index).copied(),
+            Route::Peer(peer_key) => Some(peer_key),
+        };
+        let Some(peer_key) = peer_key else {
+            return;
+        };
+
+        let session = match self.connections.entry(peer_key) {
+            hashbrown::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hashbrown::hash_map::Entry::Vacant(entry) => {
+                let Some(peer) = self.shared.peers.get(&peer_key) else {
+                    println!("rejected handshake from unknown peer {}", format_key(peer_key));
+                    return;
+                };
+
+                print_key(peer_key);
+                println!("new session for peer {}...", peer.name);
+
+                let index = next_free_index(&self.indices, &mut self.next_index, self.id, self.inboxes.len());
+                let session = match Session::new(
+                    &self.shared.private_key,
+                    peer,
+                    index,
+                    &self.shared.internal_addresses,
+                    self.shared.services.clone(),
+                    datagram.udp.clone(),
+                    datagram.remote,
+                    self.shared.udp_buffer_size,
+                    self.shared.rate_limiter.clone(),
+                ) {
+                    Ok(session) => session,
+                    Err(e) => {
+                        println!("failed to create session for peer {}: {}", peer.name, e);
+                        return;
+                    }
+                };
+
+                self.indices.insert(index, peer_key);
+                entry.insert(spawn_session(session, peer_key, self.shared.session_timeout, closed.clone()))
+            }
+        };
+
+        // a session which can't keep up loses datagrams like a congested link,
+        // one that just ended is removed once it reported back
+        let _ = session.datagrams.try_send(datagram);
+    }
+}
+
+/// Runs the session as its own task, which reports its index on `closed` when it ends.
+fn spawn_session(
+    session: Session<'static>,
+    peer_key: [u8; 32],
+    session_timeout: Duration,
+    closed: mpsc::UnboundedSender<([u8; 32], u32)>,
+) -> SessionHandle {
+    let (datagrams, receiver) = mpsc::channel(QUEUE_SIZE);
+    let index = session.index();
+    let peer_name = session.peer_name().to_owned();
+
+    tokio::spawn(async move {
+        if let Err(e) = session.run(receiver, session_timeout).await {
+            println!("dropping session of peer {}: {}", peer_name, e);
+        }
+        let _ = closed.send((peer_key, index));
+    });
+
+    SessionHandle { index, datagrams }
+}
+
+/// The worker which runs the sessions of a peer, no matter which one its
+/// handshakes arrive at.
+fn home_worker(peer_key: &[u8; 32], workers: usize) -> usize {
+    u32::from_le_bytes([peer_key[0], peer_key[1], peer_key[2], peer_key[3]]) as usize % workers
+}
+
+/// Returns an unused 24 bit session index owned by `worker`, boringtun uses
+/// the lower 8 bits of the wireguard index for the handshakes within a session.
+fn next_free_index<T>(indices: &HashMap<u32, T>, next_index: &mut u32, worker: usize, workers: usize) -> u32 {
+    loop {
+        // the worker owning an index is the index modulo the number of workers
+        let index = *next_index * workers as u32 + worker as u32;
+        *next_index += 1;
+
+        if index > 0x00ff_ffff {
+            *next_index = 0;
+            continue;
+        }
+        if !indices.contains_key(&index) {
+            return index;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,44 @@
+use super::*;
+
+#[test]
+fn indices_are_owned_by_the_worker() {
+    let indices: HashMap<u32, ()> = HashMap::new();
+    let mut next_index = 0;
+
+    let taken: Vec<_> = (0..3)
+        .map(|_| next_free_index(&indices, &mut next_index, 2, 4))
+        .collect();
+    assert_eq!(taken, [2, 6, 10]);
+}
+
+#[test]
+fn used_indices_are_skipped() {
+    let indices: HashMap<u32, ()> = [(1, ()), (4, ())].into_iter().collect();
+    let mut next_index = 0;
+
+    assert_eq!(next_free_index(&indices, &mut next_index, 1, 3), 7);
+}
+
+#[test]
+fn indices_wrap_around() {
+    let indices: HashMap<u32, ()> = HashMap::new();
+    let mut next_index = 0x00ff_ffff;
+
+    assert_eq!(next_free_index(&indices, &mut next_index, 0, 1), 0x00ff_ffff);
+    assert_eq!(next_free_index(&indices, &mut next_index, 0, 1), 0);
+}
+
+#[test]
+fn wrapped_indices_stay_with_the_worker() {
+    // the largest index of worker 3 out of 4 is 0x00ff_ffff
+    let indices: HashMap<u32, ()> = [(3, ())].into_iter().collect();
+    let mut next_index = 0x00ff_ffff / 4;
+
+    assert_eq!(next_free_index(&indices, &mut next_index, 3, 4), 0x00ff_ffff);
+    assert_eq!(next_free_index(&indices, &mut next_index, 3, 4), 7);
+
+    // worker 0 of 4 has no index above 0x00ff_fffc
+    let mut next_index = 0x00ff_fffc / 4;
+    assert_eq!(next_free_index(&indices, &mut next_index, 0, 4), 0x00ff_fffc);
+    assert_eq!(next_free_index(&indices, &mut next_index, 0, 4), 0);
+}